@@ -0,0 +1,84 @@
+//! Compares the heap-based encoder against the original rescanning one.
+//!
+//! cargo bench --features bench --bench encode -- [corpus path] [vocab size]
+//!
+//! Without a path the crate's own sources are repeated into a few MB of text.
+
+use gpt_rs::token::tokenizer::{BpeConfig, BpeTokenizer, EncodeOptions, SpecialSet};
+use std::{env, fs, time::Instant};
+
+const DEFAULT_CORPUS_SIZE: usize = 4 << 20;
+
+fn default_corpus() -> Vec<u8> {
+    let mut sources = Vec::new();
+    for dir in ["src", "src/loader", "src/matrix", "src/model", "src/token"] {
+        let mut paths: Vec<_> = fs::read_dir(dir)
+            .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+            .unwrap_or_default();
+        paths.sort();
+        for path in paths.iter().filter(|p| p.is_file()) {
+            sources.extend(fs::read(path).unwrap_or_default());
+        }
+    }
+
+    let mut corpus = Vec::with_capacity(DEFAULT_CORPUS_SIZE + sources.len());
+    while corpus.len() < DEFAULT_CORPUS_SIZE && !sources.is_empty() {
+        corpus.extend_from_slice(&sources);
+    }
+    corpus
+}
+
+fn main() {
+    // cargo passes --bench along with user arguments
+    let args: Vec<String> = env::args()
+        .skip(1)
+        .filter(|a| !a.starts_with("--"))
+        .collect();
+    let corpus = match args.first() {
+        Some(path) => fs::read(path).expect("failed to read corpus"),
+        None => default_corpus(),
+    };
+    let vocab_size = args
+        .get(1)
+        .map_or(5000, |v| v.parse().expect("invalid vocab size"));
+
+    let mut config = BpeConfig::default();
+    config.vocab_size = vocab_size;
+    let mut tokenizer = BpeTokenizer::new(config).expect("invalid config");
+
+    let start = Instant::now();
+    tokenizer.build(&corpus);
+    println!(
+        "train    {:>10.2?}  {} bytes, vocab {}",
+        start.elapsed(),
+        corpus.len(),
+        vocab_size
+    );
+
+    let start = Instant::now();
+    let baseline = tokenizer.encode_unoptimized(&corpus).unwrap();
+    let baseline_time = start.elapsed();
+    println!(
+        "rescan   {:>10.2?}  {} tokens",
+        baseline_time,
+        baseline.len()
+    );
+
+    // the rescanning encoder reads special token strings as text, and the sources contain some
+    let options = EncodeOptions {
+        disallowed_special: SpecialSet::None,
+        ..EncodeOptions::default()
+    };
+    for run in ["cold", "warm"] {
+        let start = Instant::now();
+        let tokens = tokenizer.encode_with_options(&corpus, &options).unwrap();
+        let time = start.elapsed();
+        assert_eq!(tokens, baseline, "encoders disagree");
+        println!(
+            "heap {}  {:>10.2?}  {:.1}x",
+            run,
+            time,
+            baseline_time.as_secs_f64() / time.as_secs_f64()
+        );
+    }
+}
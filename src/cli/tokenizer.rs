@@ -0,0 +1,281 @@
+use gpt_rs::token::pretokenizer::PreTokenizerKind;
+use gpt_rs::token::tokenizer::{BpeConfig, BpeTokenizer, SpecialToken, UnknownPolicy};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+};
+
+pub const USAGE: &str = "\
+Usage:
+  gpt-rs tokenizer train --vocab-size N --input FILE... [--output FILE]
+                         [--pre-tokenizer ascii|gpt2|cl100k] [--special NAME]...
+                         [--unknown byte-fallback|unk|strict]
+  gpt-rs tokenizer encode --tokenizer FILE [--output SHARD] < text
+  gpt-rs tokenizer decode --tokenizer FILE [--input SHARD] < ids
+  gpt-rs tokenizer inspect --tokenizer FILE [--top N] [--corpus FILE]
+
+Special token names eos, unk, eow and bos are the built-in tokens, anything else is used as is.
+Shards are token ids as little-endian u32s.";
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let (command, rest) = args.split_first().ok_or_else(|| USAGE.to_string())?;
+    let args = Args::parse(rest)?;
+    match command.as_str() {
+        "train" => train(&args),
+        "encode" => encode(&args),
+        "decode" => decode(&args),
+        "inspect" => inspect(&args),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn train(args: &Args) -> Result<(), String> {
+    args.expect(&[
+        "vocab-size",
+        "input",
+        "output",
+        "pre-tokenizer",
+        "special",
+        "unknown",
+    ])?;
+
+    let vocab_size = args
+        .required("vocab-size")?
+        .parse::<usize>()
+        .map_err(|_| "--vocab-size must be a number".to_string())?;
+    let pre_tokenizer = match args.get("pre-tokenizer").unwrap_or("gpt2") {
+        "ascii" => PreTokenizerKind::Ascii,
+        "gpt2" => PreTokenizerKind::Gpt2,
+        "cl100k" => PreTokenizerKind::Cl100k,
+        other => return Err(format!("Unknown pre-tokenizer {}", other)),
+    };
+    let unknown_policy = match args.get("unknown").unwrap_or("byte-fallback") {
+        "byte-fallback" => UnknownPolicy::ByteFallback,
+        "unk" => UnknownPolicy::Unk,
+        "strict" => UnknownPolicy::Strict,
+        other => return Err(format!("Unknown policy {}", other)),
+    };
+    let mut special_tokens: Vec<SpecialToken> = args
+        .all("special")
+        .into_iter()
+        .map(|name| match name {
+            "eos" => SpecialToken::Eos,
+            "unk" => SpecialToken::Unk,
+            "eow" => SpecialToken::Eow,
+            "bos" => SpecialToken::Bos,
+            name => SpecialToken::named(name),
+        })
+        .collect();
+    if special_tokens.is_empty() {
+        special_tokens.push(SpecialToken::Eos);
+    }
+
+    let inputs = args.all("input");
+    if inputs.is_empty() {
+        return Err("--input needs at least one file".to_string());
+    }
+
+    let mut tokenizer = BpeTokenizer::new(BpeConfig {
+        vocab_size,
+        special_tokens,
+        pre_tokenizer,
+        unknown_policy,
+    })?;
+    tokenizer.build_from_files(&inputs)?;
+
+    let output = args.get("output").unwrap_or("tokenizer.bin");
+    tokenizer.save_to_binary(output)?;
+    eprintln!(
+        "Trained {} tokens with {} merges, saved to {}",
+        tokenizer.vocab_size(),
+        tokenizer.merge_count(),
+        output
+    );
+    Ok(())
+}
+
+fn encode(args: &Args) -> Result<(), String> {
+    args.expect(&["tokenizer", "output"])?;
+    let tokenizer = BpeTokenizer::load_from_binary(args.required("tokenizer")?)?;
+
+    let mut data = Vec::new();
+    io::stdin()
+        .read_to_end(&mut data)
+        .map_err(|e| e.to_string())?;
+    let tokens = tokenizer.encode(&data)?;
+
+    match args.get("output") {
+        Some(path) => {
+            let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+            let mut writer = BufWriter::new(file);
+            for token in tokens.iter() {
+                writer
+                    .write_all(&token.to_le_bytes())
+                    .map_err(|e| e.to_string())?;
+            }
+            writer.flush().map_err(|e| e.to_string())?;
+            eprintln!("Wrote {} tokens to {}", tokens.len(), path);
+        }
+        None => {
+            let ids: Vec<String> = tokens.iter().map(|token| token.to_string()).collect();
+            println!("{}", ids.join(" "));
+        }
+    }
+    Ok(())
+}
+
+fn decode(args: &Args) -> Result<(), String> {
+    args.expect(&["tokenizer", "input"])?;
+    let tokenizer = BpeTokenizer::load_from_binary(args.required("tokenizer")?)?;
+
+    let tokens: Vec<u32> = match args.get("input") {
+        Some(path) => {
+            let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+            if !bytes.len().is_multiple_of(4) {
+                return Err(format!("{} is not a shard of u32 token ids", path));
+            }
+            bytes
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect()
+        }
+        None => {
+            let mut text = String::new();
+            io::stdin()
+                .read_to_string(&mut text)
+                .map_err(|e| e.to_string())?;
+            text.split_whitespace()
+                .map(|id| {
+                    id.parse::<u32>()
+                        .map_err(|_| format!("Invalid token id {}", id))
+                })
+                .collect::<Result<_, _>>()?
+        }
+    };
+
+    let bytes = tokenizer.decode(&tokens)?;
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(&bytes).map_err(|e| e.to_string())?;
+    stdout.flush().map_err(|e| e.to_string())
+}
+
+fn inspect(args: &Args) -> Result<(), String> {
+    args.expect(&["tokenizer", "top", "corpus"])?;
+    let tokenizer = BpeTokenizer::load_from_binary(args.required("tokenizer")?)?;
+    let top = match args.get("top") {
+        Some(top) => top
+            .parse::<usize>()
+            .map_err(|_| "--top must be a number".to_string())?,
+        None => 10,
+    };
+
+    let config = tokenizer.config();
+    let specials = tokenizer.special_tokens();
+    println!("vocab size: {}", tokenizer.vocab_size());
+    println!("merges: {}", tokenizer.merge_count());
+    println!("pre-tokenizer: {:?}", config.pre_tokenizer);
+    println!("unknown policy: {:?}", config.unknown_policy);
+
+    println!("special tokens:");
+    for (token, id) in specials.iter() {
+        println!("  {:>8}  {}", id, String::from_utf8_lossy(&token.repr()));
+    }
+
+    let mut longest: Vec<(u32, &[u8])> = (0..tokenizer.vocab_size() as u32)
+        .filter(|id| !specials.iter().any(|(_, special)| special == id))
+        .filter_map(|id| tokenizer.token_bytes(id).map(|bytes| (id, bytes)))
+        .collect();
+    longest.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(&b.0)));
+
+    println!("longest tokens:");
+    for (id, bytes) in longest.into_iter().take(top) {
+        println!(
+            "  {:>8}  {:>4}  {:?}",
+            id,
+            bytes.len(),
+            String::from_utf8_lossy(bytes)
+        );
+    }
+
+    if let Some(path) = args.get("corpus") {
+        let corpus = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let report = tokenizer.analyze(&corpus)?;
+        println!("corpus {}:", path);
+        print!("{}", report);
+        println!("rarest tokens:");
+        for (id, count) in report.rare(u64::MAX).into_iter().take(top) {
+            let bytes = tokenizer.token_bytes(id).unwrap_or_default();
+            println!(
+                "  {:>8}  {:>8}  {:?}",
+                id,
+                count,
+                String::from_utf8_lossy(bytes)
+            );
+        }
+    }
+    Ok(())
+}
+
+// `--flag value` pairs in order. `--input` takes every value up to the next flag, so
+// `--input a.txt b.txt` gives two inputs.
+struct Args {
+    values: Vec<(String, String)>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut values = Vec::new();
+        let mut i = 0;
+        while i < args.len() {
+            let Some(name) = args[i].strip_prefix("--") else {
+                return Err(format!("Unexpected argument {}\n\n{}", args[i], USAGE));
+            };
+
+            i += 1;
+            let start = i;
+            while i < args.len() && !args[i].starts_with("--") && (i == start || name == "input") {
+                i += 1;
+            }
+            if i == start {
+                return Err(format!("--{} needs a value", name));
+            }
+
+            for value in args[start..i].iter() {
+                values.push((name.to_string(), value.clone()));
+            }
+        }
+        Ok(Self { values })
+    }
+
+    fn expect(&self, flags: &[&str]) -> Result<(), String> {
+        match self
+            .values
+            .iter()
+            .find(|(name, _)| !flags.contains(&name.as_str()))
+        {
+            Some((name, _)) => Err(format!("Unknown flag --{}\n\n{}", name, USAGE)),
+            None => Ok(()),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(flag, _)| flag == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn all(&self, name: &str) -> Vec<&str> {
+        self.values
+            .iter()
+            .filter(|(flag, _)| flag == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    fn required(&self, name: &str) -> Result<&str, String> {
+        self.get(name)
+            .ok_or_else(|| format!("--{} is required\n\n{}", name, USAGE))
+    }
+}
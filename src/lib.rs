@@ -0,0 +1,4 @@
+pub mod loader;
+pub mod matrix;
+pub mod model;
+pub mod token;
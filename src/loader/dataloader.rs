@@ -1,8 +1,42 @@
+use crate::token::utils;
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
+use std::io::{Read, Write};
+
+const STATE_VERSION: u32 = 1;
+
+/// A batch window with the document segment id of every position in it.
+pub struct SegmentedWindow {
+    pub start: usize,
+    pub end: usize,
+    pub segments: Box<[u32]>,
+}
+
+/// Splits the token stream into `batch_size` equal tracks and slides a `sequence_length` window
+/// along all of them in lockstep. The loader guarantees that:
+/// - every window `(start, end)` and its target window `(start + 1, end + 1)` lie inside one track
+/// - every token is covered by at least one window or target window in each epoch
 pub struct DataLoader {
     tokens: Box<[u32]>,
     batch_size: usize,
     sequence_length: usize,
-    cursor: usize,
+    cursor: usize, // number of batches this shard has produced in the current epoch
+    epoch: usize,
+    rank: usize,
+    world_size: usize,
+    segments: Option<Box<[u32]>>, // document index of every token, set when eos boundaries are tracked
+    seed: Option<u64>,
+    order: Option<Box<[usize]>>, // shuffled window offsets of the current epoch
+}
+
+/// Everything needed to resume a DataLoader at the exact batch it would have produced next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataLoaderState {
+    pub cursor: usize,
+    pub epoch: usize,
+    pub seed: Option<u64>,
+    pub rank: usize,
+    pub world_size: usize,
+    pub offsets: usize, // number of window offsets, used to reject state from a different dataset layout
 }
 
 impl DataLoader {
@@ -11,12 +45,32 @@ impl DataLoader {
         sequence_length: usize,
         batch_size: usize,
     ) -> Result<Self, String> {
+        if batch_size == 0 {
+            return Err("DataLoader: batch size must be greater than 0".to_string());
+        }
+
+        if sequence_length == 0 {
+            return Err("DataLoader: sequence length must be greater than 0".to_string());
+        }
+
         if tokens.len() < batch_size {
             return Err("DataLoader: batch size is larger than tokens".to_string());
         }
 
-        if (tokens.len() / batch_size) < sequence_length {
-            return Err("DataLoader: segment length is smaller than sequence length".to_string());
+        if !tokens.len().is_multiple_of(batch_size) {
+            return Err(format!(
+                "DataLoader: {} tokens do not split evenly into {} segments, {} would be dropped",
+                tokens.len(),
+                batch_size,
+                tokens.len() % batch_size
+            ));
+        }
+
+        // each window needs one extra token after it for the shifted targets
+        if (tokens.len() / batch_size) <= sequence_length {
+            return Err(
+                "DataLoader: segment length must be greater than sequence length".to_string(),
+            );
         }
 
         Ok(DataLoader {
@@ -24,23 +78,205 @@ impl DataLoader {
             batch_size,
             sequence_length,
             cursor: 0,
+            epoch: 0,
+            rank: 0,
+            world_size: 1,
+            segments: None,
+            seed: None,
+            order: None,
+        })
+    }
+
+    /// Treats every `eos_id` token as the end of a document. The eos token belongs to the document
+    /// it closes, and the token after it starts a new one.
+    pub fn with_eos_boundaries(mut self, eos_id: u32) -> Self {
+        let mut segments = Vec::with_capacity(self.tokens.len());
+        let mut document = 0_u32;
+        for token in self.tokens.iter() {
+            segments.push(document);
+            if *token == eos_id {
+                document += 1;
+            }
+        }
+
+        self.segments = Some(segments.into_boxed_slice());
+        self
+    }
+
+    /// Restricts the loader to one worker out of `world_size`. Window offsets are dealt out
+    /// round-robin by rank, so workers never see the same window and together cover every window
+    /// exactly once per epoch.
+    pub fn with_shard(mut self, rank: usize, world_size: usize) -> Result<Self, String> {
+        if world_size == 0 {
+            return Err("DataLoader: world size must be greater than 0".to_string());
+        }
+
+        if rank >= world_size {
+            return Err(format!(
+                "DataLoader: rank {} is out of range for world size {}",
+                rank, world_size
+            ));
+        }
+
+        if world_size > self.offsets() {
+            return Err("DataLoader: world size is larger than the number of windows".to_string());
+        }
+
+        self.rank = rank;
+        self.world_size = world_size;
+        self.cursor = 0;
+        Ok(self)
+    }
+
+    /// Visits window offsets in a random order that is reshuffled every epoch. The order only
+    /// depends on `seed` and the epoch, so every shard of a run agrees on it.
+    pub fn with_shuffle(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self.shuffle();
+        self
+    }
+
+    /// Starts at `epoch` instead of 0, for loaders rebuilt every epoch over freshly tokenized data.
+    pub fn with_epoch(mut self, epoch: usize) -> Self {
+        self.epoch = epoch;
+        self.cursor = 0;
+        self.shuffle();
+        self
+    }
+
+    fn shuffle(&mut self) {
+        self.order = self.seed.map(|seed| {
+            let mut rng = StdRng::seed_from_u64(epoch_seed(seed, self.epoch));
+            let mut order: Vec<usize> = (0..self.offsets()).collect();
+            order.shuffle(&mut rng);
+            order.into_boxed_slice()
+        });
+    }
+
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn epoch(&self) -> usize {
+        self.epoch
+    }
+
+    /// Number of batches this shard produces per epoch.
+    pub fn batches_per_epoch(&self) -> usize {
+        (self.offsets() - self.rank).div_ceil(self.world_size)
+    }
+
+    /// Rewinds the shard to its first window and moves on to the next epoch.
+    pub fn next_epoch(&mut self) {
+        self.cursor = 0;
+        self.epoch += 1;
+        self.shuffle();
+    }
+
+    pub fn state(&self) -> DataLoaderState {
+        DataLoaderState {
+            cursor: self.cursor,
+            epoch: self.epoch,
+            seed: self.seed,
+            rank: self.rank,
+            world_size: self.world_size,
+            offsets: self.offsets(),
+        }
+    }
+
+    /// Resumes from a saved state. The loader must have been built over the same tokens, sequence
+    /// length and batch size as the one that produced the state.
+    pub fn restore(&mut self, state: DataLoaderState) -> Result<(), String> {
+        if state.offsets != self.offsets() {
+            return Err("DataLoader: saved state does not match the dataset layout".to_string());
+        }
+
+        if state.world_size == 0
+            || state.rank >= state.world_size
+            || state.world_size > self.offsets()
+        {
+            return Err("DataLoader: saved state has an invalid shard".to_string());
+        }
+
+        let batches = (self.offsets() - state.rank).div_ceil(state.world_size);
+        if state.cursor > batches {
+            return Err(format!(
+                "DataLoader: saved cursor {} is past the {} batches of an epoch",
+                state.cursor, batches
+            ));
+        }
+
+        self.cursor = state.cursor;
+        self.epoch = state.epoch;
+        self.seed = state.seed;
+        self.rank = state.rank;
+        self.world_size = state.world_size;
+        self.shuffle();
+        Ok(())
+    }
+
+    /// Writes the loader state so it can be stored next to the model and optimizer in a checkpoint.
+    pub fn save_state(&self, writer: &mut dyn Write) -> Result<(), String> {
+        let state = self.state();
+        utils::write_u32(writer, STATE_VERSION)?;
+        utils::write_u64(writer, state.cursor as u64)?;
+        utils::write_u64(writer, state.epoch as u64)?;
+        utils::write_u32(writer, state.seed.is_some() as u32)?;
+        utils::write_u64(writer, state.seed.unwrap_or(0))?;
+        utils::write_u64(writer, state.rank as u64)?;
+        utils::write_u64(writer, state.world_size as u64)?;
+        utils::write_u64(writer, state.offsets as u64)?;
+        Ok(())
+    }
+
+    /// Reads a state written by `save_state` from a checkpoint and resumes from it.
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> Result<(), String> {
+        let version = utils::read_u32(reader)?;
+        if version != STATE_VERSION {
+            return Err(format!("DataLoader: unsupported state version {}", version));
+        }
+
+        let cursor = utils::read_u64(reader)? as usize;
+        let epoch = utils::read_u64(reader)? as usize;
+        let has_seed = utils::read_u32(reader)? != 0;
+        let seed = utils::read_u64(reader)?;
+        let rank = utils::read_u64(reader)? as usize;
+        let world_size = utils::read_u64(reader)? as usize;
+        let offsets = utils::read_u64(reader)? as usize;
+
+        self.restore(DataLoaderState {
+            cursor,
+            epoch,
+            seed: has_seed.then_some(seed),
+            rank,
+            world_size,
+            offsets,
         })
     }
 
+    // number of distinct window offsets within a segment, shared by all shards. The last one still
+    // leaves room for the target of the final position.
+    fn offsets(&self) -> usize {
+        self.tokens.len() / self.batch_size - self.sequence_length
+    }
+
     pub fn next_batch(&mut self) -> Result<Vec<(usize, usize)>, String> {
         let mut batch: Vec<(usize, usize)> = Vec::with_capacity(self.batch_size);
         let seg_len = self.tokens.len() / self.batch_size;
 
-        if self.cursor > seg_len - self.sequence_length {
+        if self.cursor >= self.batches_per_epoch() {
             return Err("DataLoader: cursor out of range".to_string());
         }
 
+        let mut offset = self.rank + self.cursor * self.world_size;
+        if let Some(order) = self.order.as_ref() {
+            offset = order[offset];
+        }
         for i in 0..self.batch_size {
             let track = (
-                self.cursor + i * seg_len,
-                self.cursor + self.sequence_length + i * seg_len,
+                offset + i * seg_len,
+                offset + self.sequence_length + i * seg_len,
             );
-
             batch.push(track);
         }
 
@@ -48,4 +284,251 @@ impl DataLoader {
 
         Ok(batch)
     }
+
+    /// Same windows as `next_batch`, each paired with the document segment id of every position.
+    /// Ids start at 0 for the first document in the window, so a position may only attend to
+    /// earlier positions carrying the same id.
+    pub fn next_batch_with_segments(&mut self) -> Result<Vec<SegmentedWindow>, String> {
+        if self.segments.is_none() {
+            return Err("DataLoader: eos boundaries are not tracked".to_string());
+        }
+
+        let batch = self.next_batch()?;
+        let segments = self.segments.as_ref().unwrap();
+
+        Ok(batch
+            .into_iter()
+            .map(|(start, end)| {
+                let first = segments[start];
+                let ids = segments[start..end].iter().map(|id| id - first).collect();
+                SegmentedWindow {
+                    start,
+                    end,
+                    segments: ids,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Seed of the random state for one epoch of a run. Seeds and epochs are mixed so that no two
+/// (seed, epoch) pairs of nearby values share a sequence.
+pub(crate) fn epoch_seed(seed: u64, epoch: usize) -> u64 {
+    // splitmix64 finalizer
+    let mix = |mut x: u64| {
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        x ^ (x >> 31)
+    };
+    mix(seed ^ mix((epoch as u64).wrapping_add(0x9E37_79B9_7F4A_7C15)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // window offset of every batch a loader produces in its current epoch
+    fn epoch_offsets(loader: &mut DataLoader) -> Vec<usize> {
+        (0..loader.batches_per_epoch())
+            .map(|_| loader.next_batch().unwrap()[0].0)
+            .collect()
+    }
+
+    #[test]
+    fn adjacent_seeds_do_not_share_epoch_orders() {
+        let order = |seed: u64, epoch: usize| {
+            let tokens: Box<[u32]> = (0..400).collect();
+            let mut loader = DataLoader::new(tokens, 4, 2)
+                .unwrap()
+                .with_shuffle(seed)
+                .with_epoch(epoch);
+            epoch_offsets(&mut loader)
+        };
+
+        assert_ne!(order(6, 1), order(7, 0));
+        assert_ne!(order(6, 0), order(6, 1));
+        assert_eq!(order(6, 1), order(6, 1));
+    }
+
+    #[test]
+    fn segment_ids_restart_in_every_window() {
+        let tokens: Box<[u32]> = Box::new([1, 2, 0, 3, 4, 5, 0, 6, 7, 8, 9, 0]);
+        let mut loader = DataLoader::new(tokens.clone(), 8, 1).unwrap();
+        assert!(loader.next_batch_with_segments().is_err());
+
+        let mut loader = DataLoader::new(tokens, 8, 1)
+            .unwrap()
+            .with_eos_boundaries(0);
+        let segments: Vec<Vec<u32>> = (0..4)
+            .map(|_| {
+                loader.next_batch_with_segments().unwrap()[0]
+                    .segments
+                    .to_vec()
+            })
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                [0, 0, 0, 1, 1, 1, 1, 2], // 1 2 eos | 3 4 5 eos | 6
+                [0, 0, 1, 1, 1, 1, 2, 2], // starts inside the first document
+                [0, 1, 1, 1, 1, 2, 2, 2], // starts at its eos
+                [0, 0, 0, 0, 1, 1, 1, 1], // starts with the second document
+            ]
+        );
+    }
+
+    #[test]
+    fn restore_rejects_states_with_shard_issues() {
+        let tokens: Box<[u32]> = (0..40).collect();
+        let mut loader = DataLoader::new(tokens, 4, 2).unwrap(); // 16 offsets
+        let state = loader.state();
+
+        let too_many_workers = DataLoaderState {
+            world_size: 17,
+            ..state
+        };
+        assert!(loader.restore(too_many_workers).is_err());
+
+        let past_the_end = DataLoaderState {
+            cursor: 17,
+            ..state
+        };
+        assert!(loader.restore(past_the_end).is_err());
+
+        let at_the_end = DataLoaderState {
+            cursor: 16,
+            ..state
+        };
+        assert!(loader.restore(at_the_end).is_ok());
+    }
+
+    proptest! {
+        #[test]
+        fn new_accepts_exactly_the_layouts_without_loss(
+            len in 0_usize..200,
+            batch_size in 0_usize..10,
+            sequence_length in 0_usize..20,
+        ) {
+            // tracks of equal length, each longer than a window so its target fits
+            let valid = batch_size > 0
+                && sequence_length > 0
+                && len >= batch_size
+                && len.is_multiple_of(batch_size)
+                && len / batch_size > sequence_length;
+
+            let tokens: Box<[u32]> = (0..len as u32).collect();
+            let loader = DataLoader::new(tokens, sequence_length, batch_size);
+            prop_assert_eq!(loader.is_ok(), valid);
+        }
+
+        #[test]
+        fn windows_and_targets_stay_in_their_track_and_reach_every_token(
+            len in 1_usize..300,
+            batch_size in 1_usize..8,
+            sequence_length in 1_usize..20,
+            seed in proptest::option::of(any::<u64>()),
+        ) {
+            let tokens: Box<[u32]> = (0..len as u32).collect();
+            let Ok(loader) = DataLoader::new(tokens, sequence_length, batch_size) else {
+                return Ok(());
+            };
+            let mut loader = match seed {
+                Some(seed) => loader.with_shuffle(seed),
+                None => loader,
+            };
+
+            let seg_len = len / batch_size;
+            let mut reached = vec![false; len];
+            for _ in 0..loader.batches_per_epoch() {
+                let batch = loader.next_batch().unwrap();
+                prop_assert_eq!(batch.len(), batch_size);
+                for (track, (start, end)) in batch.into_iter().enumerate() {
+                    prop_assert_eq!(end - start, sequence_length);
+                    prop_assert!(start >= track * seg_len);
+                    prop_assert!(end < (track + 1) * seg_len); // target window ends at end + 1
+                    reached[start..=end].fill(true);
+                }
+            }
+            prop_assert!(reached.iter().all(|r| *r));
+            prop_assert!(loader.next_batch().is_err());
+        }
+
+        #[test]
+        fn saved_state_resumes_at_the_next_batch(
+            batch_size in 1_usize..5,
+            sequence_length in 1_usize..9,
+            extra in 1_usize..30,
+            world_size in 1_usize..4,
+            seed in proptest::option::of(any::<u64>()),
+            epochs in 0_usize..3,
+            steps in 0_usize..30,
+        ) {
+            prop_assume!(world_size <= extra);
+            let tokens: Box<[u32]> = (0..((sequence_length + extra) * batch_size) as u32).collect();
+            let build = || {
+                let loader = DataLoader::new(tokens.clone(), sequence_length, batch_size)
+                    .unwrap()
+                    .with_shard(world_size - 1, world_size)
+                    .unwrap();
+                match seed {
+                    Some(seed) => loader.with_shuffle(seed),
+                    None => loader,
+                }
+            };
+
+            let mut loader = build();
+            for _ in 0..epochs {
+                loader.next_epoch();
+            }
+            for _ in 0..steps % loader.batches_per_epoch() {
+                loader.next_batch().unwrap();
+            }
+
+            let mut checkpoint = Vec::new();
+            loader.save_state(&mut checkpoint).unwrap();
+            let mut resumed = DataLoader::new(tokens.clone(), sequence_length, batch_size).unwrap();
+            resumed.load_state(&mut checkpoint.as_slice()).unwrap();
+
+            prop_assert_eq!(resumed.state(), loader.state());
+            prop_assert_eq!(resumed.next_batch().unwrap(), loader.next_batch().unwrap());
+        }
+
+        #[test]
+        fn shards_are_disjoint_and_cover_every_offset(
+            batch_size in 1_usize..5,
+            sequence_length in 1_usize..9,
+            extra in 1_usize..30,
+            world_size in 1_usize..6,
+            seed in proptest::option::of(any::<u64>()),
+        ) {
+            let seg_len = sequence_length + extra;
+            let tokens: Box<[u32]> = (0..(seg_len * batch_size) as u32).collect();
+            prop_assume!(world_size <= extra);
+
+            let mut loaders: Vec<DataLoader> = (0..world_size)
+                .map(|rank| {
+                    let loader = DataLoader::new(tokens.clone(), sequence_length, batch_size)
+                        .unwrap()
+                        .with_shard(rank, world_size)
+                        .unwrap();
+                    match seed {
+                        Some(seed) => loader.with_shuffle(seed),
+                        None => loader,
+                    }
+                })
+                .collect();
+
+            for _ in 0..3 {
+                let mut seen = vec![0_usize; extra];
+                for loader in loaders.iter_mut() {
+                    for offset in epoch_offsets(loader) {
+                        seen[offset] += 1;
+                    }
+                    loader.next_epoch();
+                }
+                prop_assert!(seen.iter().all(|count| *count == 1), "{:?}", seen);
+            }
+        }
+    }
 }
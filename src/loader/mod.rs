@@ -1 +1,2 @@
 pub mod dataloader;
+pub mod pipeline;
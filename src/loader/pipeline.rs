@@ -0,0 +1,162 @@
+use super::dataloader::{self, DataLoader};
+use crate::token::tokenizer::{BpeTokenizer, SpecialToken, Tokenizer};
+use rand::{SeedableRng, rngs::StdRng};
+
+// encodes one document with a random segmentation
+type Sampler<T> = Box<dyn Fn(&T, &[u8], &mut StdRng) -> Result<Vec<u32>, String>>;
+
+/// Tokenizes documents for a DataLoader once per epoch. With BPE-dropout each epoch sees its own
+/// segmentation of the documents, drawn from the seed and the epoch number, so rebuilding the
+/// loader of an epoch gives the same tokens and a saved loader state can be restored on it.
+pub struct TokenPipeline<'a, T: Tokenizer> {
+    tokenizer: &'a T,
+    documents: Vec<Vec<u8>>,
+    sequence_length: usize,
+    batch_size: usize,
+    eos_id: u32,
+    sampling: Option<(Sampler<T>, u64)>, // random segmentation and its seed
+}
+
+impl<'a, T: Tokenizer> TokenPipeline<'a, T> {
+    pub fn new(
+        tokenizer: &'a T,
+        documents: Vec<Vec<u8>>,
+        sequence_length: usize,
+        batch_size: usize,
+    ) -> Result<Self, String> {
+        if documents.is_empty() {
+            return Err("TokenPipeline: no documents".to_string());
+        }
+
+        let eos_id = tokenizer
+            .special_token_id(&SpecialToken::Eos)
+            .ok_or_else(|| "TokenPipeline: the tokenizer needs an eos token".to_string())?;
+
+        Ok(Self {
+            tokenizer,
+            documents,
+            sequence_length,
+            batch_size,
+            eos_id,
+            sampling: None,
+        })
+    }
+
+    /// Every document encoded for `epoch`, each closed by eos.
+    pub fn tokens(&self, epoch: usize) -> Result<Vec<u32>, String> {
+        let mut tokens = Vec::new();
+        match &self.sampling {
+            Some((sample, seed)) => {
+                let mut rng = StdRng::seed_from_u64(dataloader::epoch_seed(*seed, epoch));
+                for document in self.documents.iter() {
+                    tokens.extend(sample(self.tokenizer, document, &mut rng)?);
+                }
+            }
+            None => {
+                for document in self.documents.iter() {
+                    tokens.extend(self.tokenizer.encode(document)?);
+                }
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// A loader over the tokens of `epoch`, positioned at that epoch. The tokens are padded with
+    /// eos up to a multiple of the batch size, so no document is cut off.
+    pub fn loader(&self, epoch: usize) -> Result<DataLoader, String> {
+        let mut tokens = self.tokens(epoch)?;
+        tokens.resize(
+            tokens.len().next_multiple_of(self.batch_size.max(1)),
+            self.eos_id,
+        );
+        Ok(DataLoader::new(
+            tokens.into_boxed_slice(),
+            self.sequence_length,
+            self.batch_size,
+        )?
+        .with_epoch(epoch))
+    }
+}
+
+impl TokenPipeline<'_, BpeTokenizer> {
+    pub fn with_dropout(mut self, dropout: f64, seed: u64) -> Result<Self, String> {
+        if !(0.0..=1.0).contains(&dropout) {
+            return Err(format!(
+                "TokenPipeline: dropout {} is not between 0 and 1",
+                dropout
+            ));
+        }
+
+        let sample: Sampler<BpeTokenizer> = Box::new(move |tokenizer, document, rng| {
+            tokenizer.encode_with_dropout(document, dropout, rng)
+        });
+        self.sampling = Some((sample, seed));
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::byte::ByteTokenizer;
+    use crate::token::tokenizer::BpeConfig;
+
+    #[test]
+    fn loaders_pad_with_eos_instead_of_dropping_tokens() {
+        let tokenizer = ByteTokenizer::new(vec![SpecialToken::Eos]).unwrap();
+        let documents = vec![b"hello world".to_vec(), b"one more document".to_vec()];
+        let pipeline = TokenPipeline::new(&tokenizer, documents, 4, 3).unwrap();
+
+        let tokens = pipeline.tokens(0).unwrap();
+        assert_eq!(tokens.len(), 30);
+        let loader = pipeline.loader(0).unwrap();
+        assert_eq!(loader.tokens().len(), 30);
+        assert_eq!(&loader.tokens()[..tokens.len()], tokens.as_slice());
+
+        let pipeline = TokenPipeline::new(&tokenizer, vec![b"abcdefghijklmno".to_vec()], 4, 3);
+        let loader = pipeline.unwrap().loader(0).unwrap();
+        let eos_id = tokenizer.special_token_id(&SpecialToken::Eos).unwrap();
+        assert_eq!(loader.tokens().len(), 18);
+        assert_eq!(&loader.tokens()[15..], [eos_id; 3]);
+    }
+
+    #[test]
+    fn dropout_epochs_are_reproducible_and_decode_to_the_documents() {
+        let documents: Vec<Vec<u8>> = [
+            "the quick brown fox jumps over the lazy dog",
+            "naïve café, déjà vu! 東京は晴れ。",
+            "the dog sleeps while the fox runs <|eos|> away",
+        ]
+        .iter()
+        .map(|text| text.as_bytes().to_vec())
+        .collect();
+        let mut tokenizer = BpeTokenizer::new(BpeConfig {
+            vocab_size: 330,
+            ..BpeConfig::default()
+        })
+        .unwrap();
+        tokenizer.build(&documents.concat().repeat(20));
+        let eos_id = tokenizer.special_token_id(&SpecialToken::Eos).unwrap();
+
+        let pipeline = |seed| {
+            TokenPipeline::new(&tokenizer, documents.clone(), 8, 2)
+                .unwrap()
+                .with_dropout(0.3, seed)
+                .unwrap()
+        };
+        let tokens = pipeline(5).tokens(0).unwrap();
+        assert_eq!(pipeline(5).tokens(0).unwrap(), tokens);
+        assert_ne!(pipeline(5).tokens(1).unwrap(), tokens);
+        assert_eq!(
+            pipeline(5).loader(3).unwrap().tokens(),
+            pipeline(5).loader(3).unwrap().tokens()
+        );
+
+        let decoded: Vec<Vec<u8>> = tokens
+            .split(|id| *id == eos_id)
+            .take(documents.len())
+            .map(|document| tokenizer.decode(document).unwrap())
+            .collect();
+        assert_eq!(decoded, documents);
+    }
+}
@@ -1,7 +1,16 @@
-mod loader;
-mod matrix;
-mod model;
+mod cli;
+
+use std::{env, process};
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("tokenizer") => cli::tokenizer::run(&args[1..]),
+        _ => Err(cli::tokenizer::USAGE.to_string()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
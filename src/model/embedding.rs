@@ -18,6 +18,26 @@ impl Embedding {
         }
     }
 
+    /// Adds randomly initialized rows for tokens appended to the vocabulary, keeping the existing rows.
+    pub fn grow(&mut self, vocab_size: usize) -> Result<(), String> {
+        if vocab_size < self.vocab_size {
+            return Err(format!(
+                "Cannot shrink the embedding from {} to {} tokens",
+                self.vocab_size, vocab_size
+            ));
+        }
+
+        let mut weights = Matrix::new(vocab_size, self.dim);
+        weights.randomize();
+        for i in 0..self.vocab_size {
+            weights.row_mut(i)?.copy_from_slice(self.weights.row(i)?);
+        }
+
+        self.weights = weights;
+        self.vocab_size = vocab_size;
+        Ok(())
+    }
+
     pub fn embed(&self, tokens: &[usize]) -> Result<Matrix, String> {
         let mut ret = Matrix::new(tokens.len(), self.dim);
 
@@ -38,3 +58,28 @@ impl Embedding {
         Ok(ret)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn growing_keeps_the_existing_rows() {
+        let bits = |embedding: &Embedding, i: usize| -> Vec<u32> {
+            let row = embedding.weights.row(i).unwrap();
+            row.iter().map(|w| w.to_bits()).collect()
+        };
+        let mut embedding = Embedding::new(4, 10);
+        let rows: Vec<Vec<u32>> = (0..10).map(|i| bits(&embedding, i)).collect();
+
+        embedding.grow(13).unwrap();
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(&bits(&embedding, i), row);
+        }
+        assert!(embedding.embed(&[12]).is_ok());
+        assert!(embedding.embed(&[13]).is_err());
+
+        assert!(embedding.grow(5).is_err());
+        assert_eq!(embedding.vocab_size, 13);
+    }
+}
@@ -0,0 +1,78 @@
+use super::tokenizer::{self, SpecialToken, Tokenizer};
+use super::utils;
+
+const MAGIC: &[u8; 8] = b"GPTRSBYT";
+const FORMAT_VERSION: u32 = 1;
+
+/// Every byte is its own token, ids 0 to 255 are the byte values and special tokens follow. Needs
+/// no training, which makes it a baseline and a stand-in for tests.
+pub struct ByteTokenizer {
+    special_tokens: Vec<SpecialToken>, // id is 256 plus the index
+}
+
+impl ByteTokenizer {
+    pub fn new(special_tokens: Vec<SpecialToken>) -> Result<Self, String> {
+        for (i, token) in special_tokens.iter().enumerate() {
+            if *token == SpecialToken::Eow {
+                return Err("The byte tokenizer has no end of word token".to_string());
+            }
+
+            if special_tokens[..i].contains(token) {
+                return Err(format!(
+                    "Special token {} is registered twice",
+                    String::from_utf8_lossy(&token.repr())
+                ));
+            }
+        }
+
+        Ok(Self { special_tokens })
+    }
+}
+
+impl Tokenizer for ByteTokenizer {
+    fn encode(&self, data: &[u8]) -> Result<Vec<u32>, String> {
+        let mut tokens = self.encode_ordinary(data)?;
+        if let Some(eos_id) = self.special_token_id(&SpecialToken::Eos) {
+            tokens.push(eos_id);
+        }
+        Ok(tokens)
+    }
+
+    fn encode_ordinary(&self, data: &[u8]) -> Result<Vec<u32>, String> {
+        Ok(data.iter().map(|b| *b as u32).collect())
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::with_capacity(tokens.len());
+        for token in tokens.iter() {
+            match *token {
+                0..256 => bytes.push(*token as u8),
+                id if (id as usize) < self.vocab_size() => {} // special token
+                id => return Err(format!("Unknown token id {}", id)),
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn vocab_size(&self) -> usize {
+        256 + self.special_tokens.len()
+    }
+
+    fn special_token_id(&self, token: &SpecialToken) -> Option<u32> {
+        self.special_tokens
+            .iter()
+            .position(|special| special == token)
+            .map(|i| 256 + i as u32)
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        let mut payload = Vec::new();
+        tokenizer::write_special_tags(&mut payload, &self.special_tokens)?;
+        utils::write_checked(path, MAGIC, FORMAT_VERSION, &payload)
+    }
+
+    fn load(path: &str) -> Result<Self, String> {
+        let (_, payload) = utils::read_checked(path, MAGIC, FORMAT_VERSION)?;
+        Self::new(tokenizer::read_special_tags(&mut payload.as_slice())?)
+    }
+}
@@ -0,0 +1,106 @@
+use std::{borrow::Borrow, collections::HashMap, hash::Hash};
+
+const NIL: usize = usize::MAX;
+
+struct Entry<K, V> {
+    key: K,
+    value: V,
+    prev: usize, // towards the most recently used entry
+    next: usize, // towards the least recently used entry
+}
+
+/// Fixed-capacity least-recently-used cache. Entries live in a slab and are chained into a
+/// doubly linked list by index, so lookups, inserts and evictions are all O(1).
+pub(super) struct LruCache<K, V> {
+    capacity: usize,
+    map: HashMap<K, usize>, // key to slab index
+    entries: Vec<Entry<K, V>>,
+    head: usize, // most recently used
+    tail: usize, // least recently used
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            map: HashMap::new(),
+            entries: Vec::new(),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    pub(super) fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = *self.map.get(key)?;
+        self.unlink(i);
+        self.push_front(i);
+        Some(&self.entries[i].value)
+    }
+
+    pub(super) fn put(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some(&i) = self.map.get(&key) {
+            self.entries[i].value = value;
+            self.unlink(i);
+            self.push_front(i);
+            return;
+        }
+
+        let i = if self.entries.len() < self.capacity {
+            self.entries.push(Entry {
+                key: key.clone(),
+                value,
+                prev: NIL,
+                next: NIL,
+            });
+            self.entries.len() - 1
+        } else {
+            // reuse the slot of the least recently used entry
+            let i = self.tail;
+            self.unlink(i);
+            self.map.remove(&self.entries[i].key);
+            self.entries[i].key = key.clone();
+            self.entries[i].value = value;
+            i
+        };
+
+        self.map.insert(key, i);
+        self.push_front(i);
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.map.clear();
+        self.entries.clear();
+        self.head = NIL;
+        self.tail = NIL;
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = (self.entries[i].prev, self.entries[i].next);
+        match prev {
+            NIL => self.head = next,
+            _ => self.entries[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            _ => self.entries[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, i: usize) {
+        self.entries[i].prev = NIL;
+        self.entries[i].next = self.head;
+        match self.head {
+            NIL => self.tail = i,
+            head => self.entries[head].prev = i,
+        }
+        self.head = i;
+    }
+}
@@ -0,0 +1,111 @@
+use super::tokenizer::{BpeTokenizer, DecodeOptions};
+
+/// Decodes tokens one at a time, e.g. while sampling. A token may end in the middle of a UTF-8
+/// sequence, so its bytes are held back until the sequence is complete. Invalid bytes come out
+/// as U+FFFD.
+pub struct Decoder<'a> {
+    tokenizer: &'a BpeTokenizer,
+    options: DecodeOptions,
+    pending: Vec<u8>, // bytes not emitted yet, an incomplete sequence at most
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(tokenizer: &'a BpeTokenizer, options: DecodeOptions) -> Self {
+        Self {
+            tokenizer,
+            options,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Adds a token and returns the text it completes, which may be empty.
+    pub fn push(&mut self, token: u32) -> Result<String, String> {
+        let bytes = self
+            .tokenizer
+            .decode_with_options(&[token], &self.options)?;
+        self.pending.extend_from_slice(&bytes);
+
+        let mut text = String::new();
+        let mut start = 0;
+        loop {
+            match std::str::from_utf8(&self.pending[start..]) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    start = self.pending.len();
+                    break;
+                }
+                Err(e) => {
+                    let valid = &self.pending[start..start + e.valid_up_to()];
+                    text.push_str(std::str::from_utf8(valid).unwrap());
+                    start += e.valid_up_to();
+                    match e.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            start += len;
+                        }
+                        None => break, // incomplete sequence at the end, wait for more bytes
+                    }
+                }
+            }
+        }
+
+        self.pending.drain(..start);
+        Ok(text)
+    }
+
+    /// Returns what is left at the end of the stream, an incomplete sequence becomes U+FFFD.
+    pub fn finish(self) -> String {
+        String::from_utf8_lossy(&self.pending).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::tokenizer::{BpeConfig, SpecialToken};
+
+    const TEXT: &str = "naïve café, 東京は晴れ 🎉 the fox <|eos|> jumps";
+
+    fn built(vocab_size: usize) -> BpeTokenizer {
+        let mut tokenizer = BpeTokenizer::new(BpeConfig {
+            vocab_size,
+            ..BpeConfig::default()
+        })
+        .unwrap();
+        tokenizer.build(TEXT.repeat(20).as_bytes());
+        tokenizer
+    }
+
+    #[test]
+    fn partial_sequences_wait_for_their_last_byte() {
+        let tokenizer = built(257); // single bytes only, ids are the bytes
+        let mut decoder = Decoder::new(&tokenizer, DecodeOptions::default());
+        let texts: Vec<String> = "東京"
+            .bytes()
+            .map(|b| decoder.push(b as u32).unwrap())
+            .collect();
+        assert_eq!(texts, ["", "", "東", "", "", "京"]);
+
+        let eos_id = tokenizer.special_token_id(&SpecialToken::Eos).unwrap();
+        assert_eq!(decoder.push(eos_id).unwrap(), "");
+        assert_eq!(decoder.push(0xFF).unwrap(), "\u{FFFD}");
+        assert_eq!(decoder.push(b'a' as u32).unwrap(), "a");
+        assert_eq!(decoder.push(0xE6).unwrap(), "");
+        assert_eq!(decoder.finish(), "\u{FFFD}");
+    }
+
+    #[test]
+    fn streamed_and_whole_decoding_give_back_the_text() {
+        let tokenizer = built(320);
+        let tokens = tokenizer.encode(TEXT.as_bytes()).unwrap();
+        assert_eq!(tokenizer.decode(&tokens).unwrap(), TEXT.as_bytes());
+
+        let mut decoder = Decoder::new(&tokenizer, DecodeOptions::default());
+        let mut text = String::new();
+        for token in tokens {
+            text.push_str(&decoder.push(token).unwrap());
+        }
+        text.push_str(&decoder.finish());
+        assert_eq!(text, TEXT);
+    }
+}
@@ -0,0 +1,357 @@
+use super::import::{self, bytes_to_unicode, unicode_to_bytes};
+use super::pretokenizer::PreTokenizerKind;
+use super::tokenizer::{BpeTokenizer, SpecialToken, UnknownPolicy};
+use serde_json::{Map, Value, json};
+use std::fs;
+
+impl BpeTokenizer {
+    /// Saves the tokenizer as a Hugging Face `tokenizer.json` with a byte-level BPE model, so it can
+    /// be loaded by the `tokenizers` library. Special tokens become added tokens and eos is
+    /// appended by the post-processor, as `encode` does. Tokenizers with an eow token or a custom
+    /// pre-tokenizer have no equivalent in that format.
+    pub fn save_to_hf_json(&self, path: &str) -> Result<(), String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        if self.special_tokens.contains_key(&SpecialToken::Eow) {
+            return Err("Tokenizers with an eow token cannot be exported to tokenizer.json".into());
+        }
+        let Some(pattern) = self.config.pre_tokenizer.pattern() else {
+            return Err(
+                "Tokenizers with a custom pre-tokenizer cannot be exported to tokenizer.json"
+                    .into(),
+            );
+        };
+
+        let alphabet = bytes_to_unicode();
+        let to_text =
+            |bytes: &[u8]| -> String { bytes.iter().map(|b| alphabet[*b as usize]).collect() };
+
+        let mut specials: Vec<(&SpecialToken, u32)> = self
+            .special_tokens
+            .iter()
+            .map(|(token, id)| (token, *id))
+            .collect();
+        specials.sort_by_key(|(_, id)| *id);
+
+        let mut vocab = Map::new();
+        for (id, token_bytes) in self.i2t.iter().enumerate() {
+            if !token_bytes.is_empty() && !specials.iter().any(|(_, s)| *s == id as u32) {
+                vocab.insert(to_text(token_bytes), json!(id));
+            }
+        }
+
+        let mut added_tokens = Vec::with_capacity(specials.len());
+        for (token, id) in specials.iter() {
+            // a regular token can spell the same text, it keeps the entry and the special token is
+            // only known as an added token
+            let content = String::from_utf8_lossy(&token.repr()).into_owned();
+            if !vocab.contains_key(&content) {
+                vocab.insert(content.clone(), json!(id));
+            } else if **token == SpecialToken::Unk {
+                return Err(format!("A regular token has the same text as {}", content));
+            }
+            added_tokens.push(json!({
+                "id": id,
+                "content": content,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            }));
+        }
+
+        let mut merges: Vec<(&(u32, u32), &usize)> = self.merge_rank.iter().collect();
+        merges.sort_by_key(|(_, rank)| **rank);
+        let merges: Vec<String> = merges
+            .into_iter()
+            .map(|((a, b), _)| {
+                format!(
+                    "{} {}",
+                    to_text(&self.i2t[*a as usize]),
+                    to_text(&self.i2t[*b as usize])
+                )
+            })
+            .collect();
+
+        let byte_level = |use_regex: bool| {
+            json!({
+                "type": "ByteLevel",
+                "add_prefix_space": false,
+                "trim_offsets": true,
+                "use_regex": use_regex,
+            })
+        };
+        let split = |pattern: &str| {
+            json!({
+                "type": "Split",
+                "pattern": { "Regex": pattern },
+                "behavior": "Isolated",
+                "invert": false,
+            })
+        };
+        // the ascii pattern works on bytes, so it runs on the byte-level chars where every
+        // alphanumeric stays itself and every other byte becomes one char
+        let pre_tokenizer = match self.config.pre_tokenizer {
+            PreTokenizerKind::Gpt2 => byte_level(true),
+            PreTokenizerKind::Ascii => json!({
+                "type": "Sequence",
+                "pretokenizers": [byte_level(false), split(pattern)],
+            }),
+            _ => json!({
+                "type": "Sequence",
+                "pretokenizers": [split(pattern), byte_level(false)],
+            }),
+        };
+
+        let post_processor = match self.special_tokens.get(&SpecialToken::Eos) {
+            Some(eos_id) => {
+                let eos = String::from_utf8_lossy(&SpecialToken::Eos.repr()).into_owned();
+                let sequence = |id: &str| json!({ "Sequence": { "id": id, "type_id": 0 } });
+                let special = json!({ "SpecialToken": { "id": eos, "type_id": 0 } });
+                json!({
+                    "type": "TemplateProcessing",
+                    "single": [sequence("A"), special],
+                    "pair": [sequence("A"), special, sequence("B"), special],
+                    "special_tokens": {
+                        eos.clone(): { "id": eos, "ids": [eos_id], "tokens": [eos] },
+                    },
+                })
+            }
+            None => Value::Null,
+        };
+
+        let unk_token = match self.config.unknown_policy {
+            UnknownPolicy::Unk => json!(String::from_utf8_lossy(&SpecialToken::Unk.repr())),
+            _ => Value::Null,
+        };
+
+        let tokenizer = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": added_tokens,
+            "normalizer": null,
+            "pre_tokenizer": pre_tokenizer,
+            "post_processor": post_processor,
+            "decoder": byte_level(true),
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": unk_token,
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "vocab": vocab,
+                "merges": merges,
+            },
+        });
+
+        let json = serde_json::to_string_pretty(&tokenizer).map_err(|e| e.to_string())?;
+        fs::write(path, json).map_err(|e| e.to_string())
+    }
+
+    /// Loads a byte-level BPE `tokenizer.json`, such as one written by `save_to_hf_json` or GPT-2
+    /// style tokenizers from the Hugging Face hub. Added tokens become special tokens, and the
+    /// pre-tokenizer has to be one of the splitters available here.
+    pub fn from_hf_json(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let tokenizer: Value = serde_json::from_str(&contents).map_err(|e| e.to_string())?;
+
+        let model = &tokenizer["model"];
+        if model["type"] != "BPE" {
+            return Err("Only BPE models can be loaded from tokenizer.json".to_string());
+        }
+        let pre_tokenizer = pre_tokenizer_kind(&tokenizer["pre_tokenizer"])?;
+
+        let mut specials = Vec::new();
+        for added in tokenizer["added_tokens"].as_array().into_iter().flatten() {
+            let (Some(content), Some(id)) = (added["content"].as_str(), added["id"].as_u64())
+            else {
+                return Err("Invalid added token in tokenizer.json".to_string());
+            };
+            let token = [SpecialToken::Eos, SpecialToken::Unk, SpecialToken::Bos]
+                .into_iter()
+                .find(|token| token.repr() == content.as_bytes())
+                .unwrap_or_else(|| SpecialToken::named(content));
+            specials.push((token, id as u32));
+        }
+
+        let vocab_map = model["vocab"]
+            .as_object()
+            .ok_or_else(|| "tokenizer.json has no BPE vocabulary".to_string())?;
+
+        // a model with an unk token maps missing bytes to it, whatever its name
+        let mut unknown_policy = UnknownPolicy::ByteFallback;
+        if let Some(name) = model["unk_token"].as_str() {
+            let added = specials
+                .iter_mut()
+                .find(|(token, _)| token.repr() == name.as_bytes());
+            match (added, vocab_map.get(name).and_then(Value::as_u64)) {
+                (Some((token, _)), _) => *token = SpecialToken::Unk,
+                (None, Some(id)) => specials.push((SpecialToken::Unk, id as u32)),
+                (None, None) => {
+                    return Err(format!("Unknown token {:?} is not in the vocabulary", name));
+                }
+            }
+            unknown_policy = UnknownPolicy::Unk;
+        }
+        let mut vocab = Vec::with_capacity(vocab_map.len());
+        for (token, id) in vocab_map.iter() {
+            let id = id
+                .as_u64()
+                .ok_or_else(|| format!("Token {:?} has an invalid id", token))?
+                as u32;
+            if !specials.iter().any(|(_, special_id)| *special_id == id) {
+                vocab.push((unicode_to_bytes(token)?, id));
+            }
+        }
+
+        // merges are either "a b" strings or, in newer files, [a, b] pairs
+        let mut merges = Vec::new();
+        for merge in model["merges"].as_array().into_iter().flatten() {
+            match merge {
+                Value::String(line) => {
+                    merges.extend(import::parse_merges([line.as_str()].into_iter())?)
+                }
+                Value::Array(pair) if pair.len() == 2 => {
+                    let (Some(a), Some(b)) = (pair[0].as_str(), pair[1].as_str()) else {
+                        return Err("Invalid merge in tokenizer.json".to_string());
+                    };
+                    merges.push((unicode_to_bytes(a)?, unicode_to_bytes(b)?));
+                }
+                _ => return Err("Invalid merge in tokenizer.json".to_string()),
+            }
+        }
+
+        Self::from_vocab(vocab, merges, specials, pre_tokenizer, unknown_policy)
+    }
+}
+
+// the splitter equivalent to a byte-level pre-tokenizer config
+fn pre_tokenizer_kind(config: &Value) -> Result<PreTokenizerKind, String> {
+    let unsupported = || "Unsupported pre-tokenizer in tokenizer.json".to_string();
+    match config["type"].as_str() {
+        Some("ByteLevel") if config["use_regex"] != false => Ok(PreTokenizerKind::Gpt2),
+        Some("Sequence") => {
+            let steps = config["pretokenizers"].as_array().ok_or_else(unsupported)?;
+            if !steps.iter().any(|step| step["type"] == "ByteLevel") {
+                return Err(unsupported());
+            }
+
+            // the ascii pattern splits the byte-level chars, the others the text before it
+            let byte_level = steps.iter().position(|step| step["type"] == "ByteLevel");
+            let split = steps
+                .iter()
+                .position(|step| step["type"] == "Split")
+                .and_then(|i| Some((i, steps[i]["pattern"]["Regex"].as_str()?)));
+            match split {
+                Some((i, pattern)) => [
+                    PreTokenizerKind::Ascii,
+                    PreTokenizerKind::Gpt2,
+                    PreTokenizerKind::Cl100k,
+                ]
+                .into_iter()
+                .find(|kind| {
+                    kind.pattern() == Some(pattern)
+                        && (*kind == PreTokenizerKind::Ascii) == (byte_level < Some(i))
+                })
+                .ok_or_else(unsupported),
+                None if steps
+                    .iter()
+                    .any(|step| step["type"] == "ByteLevel" && step["use_regex"] != false) =>
+                {
+                    Ok(PreTokenizerKind::Gpt2)
+                }
+                None => Err(unsupported()),
+            }
+        }
+        _ => Err(unsupported()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::tokenizer::{BpeConfig, DecodeOptions, EncodeOptions, SpecialSet};
+    use crate::token::utils::TempFile;
+
+    const TEXT: &str =
+        "The quick brown fox, it's 2024! naïve café déjà vu 東京は晴れ。 🎉\n\n  ok <|eos|>";
+
+    fn round_trip(tokenizer: &BpeTokenizer, name: &str) -> BpeTokenizer {
+        let path = TempFile::new(name);
+        tokenizer.save_to_hf_json(&path).unwrap();
+        BpeTokenizer::from_hf_json(&path).unwrap()
+    }
+
+    #[test]
+    fn exported_tokenizers_load_and_encode_the_same() {
+        let corpus = TEXT.repeat(30);
+        for kind in [
+            PreTokenizerKind::Ascii,
+            PreTokenizerKind::Gpt2,
+            PreTokenizerKind::Cl100k,
+        ] {
+            let mut tokenizer = BpeTokenizer::new(BpeConfig {
+                vocab_size: 320,
+                special_tokens: vec![SpecialToken::Eos, SpecialToken::Unk],
+                pre_tokenizer: kind.clone(),
+                ..BpeConfig::default()
+            })
+            .unwrap();
+            tokenizer.build_with_threads(corpus.as_bytes(), 2);
+
+            let loaded = round_trip(&tokenizer, &format!("{:?}.json", kind));
+            let options = EncodeOptions {
+                allowed_special: SpecialSet::All,
+                ..EncodeOptions::default()
+            };
+            assert_eq!(
+                loaded.encode_with_options(TEXT.as_bytes(), &options),
+                tokenizer.encode_with_options(TEXT.as_bytes(), &options),
+                "{:?}",
+                kind
+            );
+            assert_eq!(loaded.vocab_size(), tokenizer.vocab_size());
+        }
+    }
+
+    #[test]
+    fn regular_tokens_spelling_a_special_token_survive_export() {
+        // byte tokens, then merges that spell <|eos|> one byte at a time
+        let name = SpecialToken::Eos.repr();
+        let mut vocab: Vec<(Vec<u8>, u32)> = (0..=255).map(|b| (vec![b], b as u32)).collect();
+        let mut merges = Vec::new();
+        for len in 2..=name.len() {
+            merges.push((name[..len - 1].to_vec(), name[len - 1..len].to_vec()));
+            vocab.push((name[..len].to_vec(), vocab.len() as u32));
+        }
+        let eos_id = vocab.len() as u32;
+        let tokenizer = BpeTokenizer::from_vocab(
+            vocab,
+            merges,
+            vec![(SpecialToken::Eos, eos_id)],
+            PreTokenizerKind::Gpt2,
+            UnknownPolicy::ByteFallback,
+        )
+        .unwrap();
+
+        let loaded = round_trip(&tokenizer, "spelled.json");
+        let keep = DecodeOptions {
+            skip_special: false,
+        };
+        assert_eq!(loaded.special_token_id(&SpecialToken::Eos), Some(eos_id));
+        assert_eq!(
+            loaded.decode_with_options(&[eos_id - 1], &keep).unwrap(),
+            name
+        );
+        assert_eq!(
+            loaded.encode(b"a <|eos").unwrap(),
+            tokenizer.encode(b"a <|eos").unwrap()
+        );
+    }
+}
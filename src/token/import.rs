@@ -0,0 +1,348 @@
+use super::pretokenizer::PreTokenizerKind;
+use super::tokenizer::{BpeConfig, BpeTokenizer, SpecialToken, UnknownPolicy};
+use super::utils;
+use serde_json::Value;
+use std::{collections::HashMap, fs};
+
+/// Special tokens that GPT-2's `encoder.json` lists among the regular vocabulary.
+const GPT2_SPECIAL_TOKENS: [&str; 1] = ["<|endoftext|>"];
+
+type Merges = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// GPT-2's reversible mapping from bytes to printable chars, which its vocabulary files (and
+/// byte-level Hugging Face tokenizers) use to store raw token bytes as text.
+pub fn bytes_to_unicode() -> [char; 256] {
+    let mut chars = ['\0'; 256];
+    let mut n = 0;
+    for b in 0..256_u32 {
+        let printable = (b'!' as u32..=b'~' as u32).contains(&b)
+            || (0xA1..=0xAC).contains(&b)
+            || (0xAE..=0xFF).contains(&b);
+        chars[b as usize] = if printable {
+            char::from_u32(b).unwrap()
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+    }
+    chars
+}
+
+pub(super) fn unicode_to_bytes(text: &str) -> Result<Vec<u8>, String> {
+    let chars = bytes_to_unicode();
+    let inverse: HashMap<char, u8> = chars
+        .iter()
+        .enumerate()
+        .map(|(b, c)| (*c, b as u8))
+        .collect();
+
+    text.chars()
+        .map(|c| {
+            inverse
+                .get(&c)
+                .copied()
+                .ok_or_else(|| format!("Character {:?} is not in the byte-level alphabet", c))
+        })
+        .collect()
+}
+
+impl BpeTokenizer {
+    /// Loads GPT-2's `encoder.json` and `vocab.bpe`. Token ids are the reference ids, and
+    /// `<|endoftext|>` is registered as a named special token.
+    pub fn from_gpt2(encoder_path: &str, vocab_path: &str) -> Result<Self, String> {
+        let encoder = fs::read_to_string(encoder_path).map_err(|e| e.to_string())?;
+        let encoder: Value = serde_json::from_str(&encoder).map_err(|e| e.to_string())?;
+        let encoder = encoder
+            .as_object()
+            .ok_or_else(|| "encoder.json must be an object".to_string())?;
+
+        let mut vocab = Vec::with_capacity(encoder.len());
+        let mut specials = Vec::new();
+        for (token, id) in encoder.iter() {
+            let id = id
+                .as_u64()
+                .ok_or_else(|| format!("Token {:?} has an invalid id", token))?
+                as u32;
+            if GPT2_SPECIAL_TOKENS.contains(&token.as_str()) {
+                specials.push((SpecialToken::named(token), id));
+            } else {
+                vocab.push((unicode_to_bytes(token)?, id));
+            }
+        }
+
+        let merges = fs::read_to_string(vocab_path).map_err(|e| e.to_string())?;
+        let merges = parse_merges(merges.lines())?;
+
+        Self::from_vocab(
+            vocab,
+            merges,
+            specials,
+            PreTokenizerKind::Gpt2,
+            UnknownPolicy::ByteFallback,
+        )
+    }
+
+    /// Loads a tiktoken `.tiktoken` file of base64 tokens and their ranks. The ranks are the
+    /// token ids, and the merge producing each token is recovered by running BPE over its bytes
+    /// with the lower ranked tokens. Special tokens are not part of the file and have to be given
+    /// with their ids, e.g. `("<|endoftext|>", 100257)` for cl100k_base.
+    pub fn from_tiktoken(
+        path: &str,
+        special_tokens: &[(&str, u32)],
+        pre_tokenizer: PreTokenizerKind,
+    ) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut vocab = Vec::new();
+        for line in contents.lines().filter(|line| !line.is_empty()) {
+            let (token, rank) = line
+                .split_once(' ')
+                .ok_or_else(|| format!("Invalid tiktoken line {:?}", line))?;
+            let rank = rank
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid rank in tiktoken line {:?}", line))?;
+            vocab.push((utils::base64_decode(token)?, rank));
+        }
+        vocab.sort_by_key(|(_, rank)| *rank);
+
+        let ranks: HashMap<&[u8], u32> = vocab
+            .iter()
+            .map(|(bytes, rank)| (bytes.as_slice(), *rank))
+            .collect();
+        let mut merges = Vec::new();
+        for (bytes, rank) in vocab.iter().filter(|(bytes, _)| bytes.len() > 1) {
+            let parts = byte_pair_merge(&ranks, bytes, *rank);
+            if parts.len() != 2 {
+                return Err(format!(
+                    "Token {:?} with rank {} is not a merge of two lower ranked tokens",
+                    String::from_utf8_lossy(bytes),
+                    rank
+                ));
+            }
+            merges.push((parts[0].to_vec(), parts[1].to_vec()));
+        }
+
+        let specials = special_tokens
+            .iter()
+            .map(|(name, id)| (SpecialToken::named(name), *id))
+            .collect();
+        Self::from_vocab(
+            vocab,
+            merges,
+            specials,
+            pre_tokenizer,
+            UnknownPolicy::ByteFallback,
+        )
+    }
+
+    /// Builds a tokenizer from token bytes with their ids, merges in rank order and special tokens
+    /// with their ids. Ids are kept as given, gaps are filled with empty tokens. Bytes missing from
+    /// the vocabulary are handled by `unknown_policy`.
+    pub(super) fn from_vocab(
+        vocab: Vec<(Vec<u8>, u32)>,
+        merges: Merges,
+        specials: Vec<(SpecialToken, u32)>,
+        pre_tokenizer: PreTokenizerKind,
+        unknown_policy: UnknownPolicy,
+    ) -> Result<Self, String> {
+        let size = vocab
+            .iter()
+            .map(|(_, id)| *id)
+            .chain(specials.iter().map(|(_, id)| *id))
+            .max()
+            .map_or(0, |id| id as usize + 1);
+
+        let mut i2t: Vec<Box<[u8]>> = vec![Box::new([]); size];
+        let mut t2i: HashMap<&[u8], u32> = HashMap::with_capacity(vocab.len());
+        for (bytes, id) in vocab.iter() {
+            i2t[*id as usize] = bytes.clone().into_boxed_slice();
+            t2i.insert(bytes, *id);
+        }
+
+        let byte_ids = (0..=255_u8)
+            .map(|b| t2i.get([b].as_slice()).copied())
+            .collect();
+
+        let mut merge_rank = HashMap::with_capacity(merges.len());
+        let mut pair_to_token = HashMap::with_capacity(merges.len());
+        for (rank, (a, b)) in merges.iter().enumerate() {
+            let lookup = |bytes: &[u8]| {
+                t2i.get(bytes).copied().ok_or_else(|| {
+                    format!(
+                        "Merge {:?} + {:?} uses a token missing from the vocabulary",
+                        String::from_utf8_lossy(a),
+                        String::from_utf8_lossy(b)
+                    )
+                })
+            };
+            let pair = (lookup(a)?, lookup(b)?);
+            let merged = lookup(&[a.as_slice(), b.as_slice()].concat())?;
+            merge_rank.entry(pair).or_insert(rank);
+            pair_to_token.entry(pair).or_insert(merged);
+        }
+
+        let mut specials = specials;
+        specials.sort_by_key(|(_, id)| *id);
+        let mut special_tokens = HashMap::with_capacity(specials.len());
+        for (token, id) in specials.iter() {
+            i2t[*id as usize] = token.repr().into_boxed_slice();
+            special_tokens.insert(token.clone(), *id);
+        }
+
+        let mut tokenizer = Self::from_parts(
+            BpeConfig {
+                vocab_size: size,
+                special_tokens: specials.into_iter().map(|(token, _)| token).collect(),
+                pre_tokenizer,
+                unknown_policy,
+            },
+            i2t,
+            special_tokens,
+            merge_rank,
+            pair_to_token,
+            byte_ids,
+        );
+        tokenizer.apply_unknown_policy()?;
+        Ok(tokenizer)
+    }
+}
+
+// parses `a b` merge lines written with the byte-level alphabet, skipping the version header
+pub(super) fn parse_merges<'a>(lines: impl Iterator<Item = &'a str>) -> Result<Merges, String> {
+    let mut merges = Vec::new();
+    for line in lines {
+        if line.starts_with("#version") || line.trim().is_empty() {
+            continue;
+        }
+
+        let (a, b) = line
+            .split_once(' ')
+            .ok_or_else(|| format!("Invalid merge line {:?}", line))?;
+        merges.push((unicode_to_bytes(a)?, unicode_to_bytes(b)?));
+    }
+    Ok(merges)
+}
+
+// tiktoken's merge loop: repeatedly joins the adjacent parts whose concatenation has the lowest
+// rank below `max_rank`
+fn byte_pair_merge<'a>(
+    ranks: &HashMap<&[u8], u32>,
+    piece: &'a [u8],
+    max_rank: u32,
+) -> Vec<&'a [u8]> {
+    let mut bounds: Vec<usize> = (0..=piece.len()).collect();
+    loop {
+        let best = (0..bounds.len().saturating_sub(2))
+            .filter_map(|i| {
+                ranks
+                    .get(&piece[bounds[i]..bounds[i + 2]])
+                    .filter(|rank| **rank < max_rank)
+                    .map(|rank| (*rank, i))
+            })
+            .min();
+
+        match best {
+            Some((_, i)) => {
+                bounds.remove(i + 1);
+            }
+            None => break,
+        }
+    }
+
+    bounds.windows(2).map(|w| &piece[w[0]..w[1]]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::tokenizer::{EncodeOptions, SpecialSet};
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/");
+
+    // reference ids from GPT-2's encoder.py and tiktoken's merge loop over the fixture files
+    const GPT2_CASES: &[(&str, &[u32])] = &[
+        (
+            "The lazy fox sleeps, and it's 2024.",
+            &[
+                314, 374, 88, 276, 274, 75, 277, 320, 11, 364, 370, 6, 82, 361, 13,
+            ],
+        ),
+        (
+            "Привет всем, мир!",
+            &[140, 253, 344, 311, 141, 223, 270, 140, 120, 11, 313, 0],
+        ),
+        (
+            "你好，我们的世界。",
+            &[346, 348, 162, 230, 239, 160, 119, 105, 347, 226, 272, 290],
+        ),
+        (
+            "こんにちは、Καλημέρα 🎉 naïve café",
+            &[345, 271, 223, 138, 248, 341, 302, 354, 305],
+        ),
+    ];
+    const CL100K_CASES: &[(&str, &[u32])] = &[
+        (
+            "The lazy fox sleeps, and it's 2024.",
+            &[
+                315, 374, 277, 275, 75, 278, 321, 11, 362, 368, 6, 82, 220, 17, 15, 17, 19, 13,
+            ],
+        ),
+        (
+            "Привет всем, мир!",
+            &[140, 253, 345, 312, 141, 223, 271, 140, 120, 11, 314, 0],
+        ),
+        (
+            "你好，我们的世界。",
+            &[348, 350, 162, 230, 239, 160, 119, 105, 349, 226, 273, 291],
+        ),
+        (
+            "こんにちは、Καλημέρα 🎉 naïve café",
+            &[347, 272, 223, 138, 248, 342, 303, 355, 306],
+        ),
+    ];
+
+    fn check(tokenizer: &BpeTokenizer, cases: &[(&str, &[u32])]) {
+        for (text, ids) in cases {
+            assert_eq!(tokenizer.encode(text.as_bytes()).unwrap(), *ids, "{}", text);
+            assert_eq!(tokenizer.decode(ids).unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn gpt2_files_reproduce_the_reference_ids() {
+        let tokenizer = BpeTokenizer::from_gpt2(
+            &format!("{}gpt2_encoder.json", FIXTURES),
+            &format!("{}gpt2_vocab.bpe", FIXTURES),
+        )
+        .unwrap();
+        check(&tokenizer, GPT2_CASES);
+
+        let options = EncodeOptions {
+            allowed_special: SpecialSet::All,
+            ..EncodeOptions::default()
+        };
+        let ids = tokenizer
+            .encode_with_options(b"The fox<|endoftext|>", &options)
+            .unwrap();
+        assert_eq!(ids, [314, 276, 376]);
+        let options = EncodeOptions {
+            disallowed_special: SpecialSet::All,
+            ..EncodeOptions::default()
+        };
+        assert!(
+            tokenizer
+                .encode_with_options(b"The fox<|endoftext|>", &options)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn tiktoken_files_reproduce_the_reference_ids() {
+        let tokenizer = BpeTokenizer::from_tiktoken(
+            &format!("{}cl100k_sample.tiktoken", FIXTURES),
+            &[("<|endoftext|>", 376)],
+            PreTokenizerKind::Cl100k,
+        )
+        .unwrap();
+        check(&tokenizer, CL100K_CASES);
+    }
+}
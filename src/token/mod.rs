@@ -1,2 +1,12 @@
+pub mod byte;
+mod cache;
+pub mod chat;
+pub mod decoder;
+pub mod hf;
+pub mod import;
+pub mod pretokenizer;
+pub mod stats;
 pub mod tokenizer;
-mod utils;
+mod train;
+pub mod unigram;
+pub(crate) mod utils;
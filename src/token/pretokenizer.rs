@@ -0,0 +1,557 @@
+use std::{fmt, mem, sync::Arc};
+use unicode_general_category::{GeneralCategory, get_general_category};
+
+/// Splits raw input into words before BPE runs. Merges never cross the boundary between two
+/// words, so the splitter decides which byte sequences can ever become a single token.
+pub trait PreTokenizer: Sync {
+    /// Returns the `(start, end)` byte range of every word, in order and covering all of `data`.
+    fn split(&self, data: &[u8]) -> Vec<(usize, usize)>;
+
+    /// Returns the first position at or after `from` where `data` can be cut in two without
+    /// changing how it splits, or `data.len()` if there is none. A cut found in a prefix of some
+    /// text is also valid for the whole text. The default cuts where all the splitters here end a
+    /// word: before a space followed by a letter, after a line feed between a non-whitespace char
+    /// and a letter, and between a letter and a char that is neither letter, number nor whitespace,
+    /// such as CJK punctuation.
+    fn boundary(&self, data: &[u8], from: usize) -> usize {
+        (from.max(1)..data.len())
+            .find(|i| is_boundary(data, *i))
+            .unwrap_or(data.len())
+    }
+}
+
+const ASCII_PATTERN: &str = r"[A-Za-z0-9]+|[^A-Za-z0-9]";
+const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+#[derive(Clone)]
+pub enum PreTokenizerKind {
+    Ascii,
+    Gpt2,
+    Cl100k,
+    /// Any other splitter. Tokenizers using one train and encode as usual, but saving or
+    /// exporting them is an error, since the file could not say how to split.
+    Custom(Arc<dyn PreTokenizer + Send + Sync>),
+}
+
+impl PreTokenizerKind {
+    pub fn get(&self) -> &dyn PreTokenizer {
+        match self {
+            PreTokenizerKind::Ascii => &AsciiPreTokenizer,
+            PreTokenizerKind::Gpt2 => &Gpt2PreTokenizer,
+            PreTokenizerKind::Cl100k => &Cl100kPreTokenizer,
+            PreTokenizerKind::Custom(pre_tokenizer) => pre_tokenizer.as_ref(),
+        }
+    }
+
+    /// The regular expression the splitter is equivalent to, as used by other tokenizer libraries.
+    /// The ascii pattern matches bytes, so it only applies to text with one char per byte. Custom
+    /// splitters have none.
+    pub fn pattern(&self) -> Option<&'static str> {
+        match self {
+            PreTokenizerKind::Ascii => Some(ASCII_PATTERN),
+            PreTokenizerKind::Gpt2 => Some(GPT2_PATTERN),
+            PreTokenizerKind::Cl100k => Some(CL100K_PATTERN),
+            PreTokenizerKind::Custom(_) => None,
+        }
+    }
+
+    pub fn to_u8(&self) -> Result<u8, String> {
+        match self {
+            PreTokenizerKind::Ascii => Ok(0),
+            PreTokenizerKind::Gpt2 => Ok(1),
+            PreTokenizerKind::Cl100k => Ok(2),
+            PreTokenizerKind::Custom(_) => {
+                Err("Tokenizers with a custom pre-tokenizer cannot be saved".to_string())
+            }
+        }
+    }
+
+    pub fn from_u8(byte: u8) -> Result<Self, String> {
+        match byte {
+            0 => Ok(PreTokenizerKind::Ascii),
+            1 => Ok(PreTokenizerKind::Gpt2),
+            2 => Ok(PreTokenizerKind::Cl100k),
+            _ => Err("Invalid pre-tokenizer byte".to_string()),
+        }
+    }
+}
+
+impl fmt::Debug for PreTokenizerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreTokenizerKind::Ascii => write!(f, "Ascii"),
+            PreTokenizerKind::Gpt2 => write!(f, "Gpt2"),
+            PreTokenizerKind::Cl100k => write!(f, "Cl100k"),
+            PreTokenizerKind::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+// custom splitters are only equal to themselves
+impl PartialEq for PreTokenizerKind {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (PreTokenizerKind::Custom(a), PreTokenizerKind::Custom(b)) => Arc::ptr_eq(a, b),
+            (a, b) => mem::discriminant(a) == mem::discriminant(b),
+        }
+    }
+}
+
+impl Eq for PreTokenizerKind {}
+
+/// Runs of ASCII alphanumerics form words, every other byte is a word of its own.
+pub struct AsciiPreTokenizer;
+
+impl PreTokenizer for AsciiPreTokenizer {
+    fn split(&self, data: &[u8]) -> Vec<(usize, usize)> {
+        let mut words = Vec::new();
+        let mut start = 0;
+        for (i, c) in data.iter().enumerate() {
+            if !c.is_ascii_alphanumeric() {
+                if start < i {
+                    words.push((start, i));
+                }
+                words.push((i, i + 1));
+                start = i + 1;
+            }
+        }
+
+        if start < data.len() {
+            words.push((start, data.len()));
+        }
+
+        words
+    }
+}
+
+/// The GPT-2 splitter, equivalent to the pattern
+/// `'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+`.
+pub struct Gpt2PreTokenizer;
+
+impl PreTokenizer for Gpt2PreTokenizer {
+    fn split(&self, data: &[u8]) -> Vec<(usize, usize)> {
+        let text = Text::new(data);
+        let mut words = Vec::new();
+        let mut i = 0;
+        while i < text.len() {
+            let end = text
+                .contraction(i, false)
+                .or_else(|| text.prefixed_run(i, Class::Letter))
+                .or_else(|| text.prefixed_run(i, Class::Number))
+                .or_else(|| text.prefixed_run(i, Class::Other))
+                .unwrap_or_else(|| text.whitespace(i));
+            words.push((text.offset(i), text.offset(end)));
+            i = end;
+        }
+
+        words
+    }
+}
+
+/// The GPT-4 (cl100k) splitter, equivalent to the pattern
+/// `(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+`.
+pub struct Cl100kPreTokenizer;
+
+impl PreTokenizer for Cl100kPreTokenizer {
+    fn split(&self, data: &[u8]) -> Vec<(usize, usize)> {
+        let text = Text::new(data);
+        let mut words = Vec::new();
+        let mut i = 0;
+        while i < text.len() {
+            let end = text
+                .contraction(i, true)
+                .or_else(|| text.letters(i))
+                .or_else(|| text.digits(i))
+                .or_else(|| {
+                    text.prefixed_run(i, Class::Other)
+                        .map(|end| text.run_while(end, |c| c == Some('\r') || c == Some('\n')))
+                })
+                .or_else(|| text.newlines(i))
+                .unwrap_or_else(|| text.whitespace(i));
+            words.push((text.offset(i), text.offset(end)));
+            i = end;
+        }
+
+        words
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Letter,
+    Number,
+    Whitespace,
+    Other,
+}
+
+// `\p{L}`, `\p{N}` and `\s` by general category and the White_Space property
+fn class(c: Option<char>) -> Class {
+    let Some(c) = c else {
+        return Class::Other;
+    };
+
+    if c.is_ascii() {
+        return match c {
+            'A'..='Z' | 'a'..='z' => Class::Letter,
+            '0'..='9' => Class::Number,
+            c if c.is_whitespace() => Class::Whitespace,
+            _ => Class::Other,
+        };
+    }
+
+    match get_general_category(c) {
+        GeneralCategory::UppercaseLetter
+        | GeneralCategory::LowercaseLetter
+        | GeneralCategory::TitlecaseLetter
+        | GeneralCategory::ModifierLetter
+        | GeneralCategory::OtherLetter => Class::Letter,
+        GeneralCategory::DecimalNumber
+        | GeneralCategory::LetterNumber
+        | GeneralCategory::OtherNumber => Class::Number,
+        _ if c.is_whitespace() => Class::Whitespace,
+        _ => Class::Other,
+    }
+}
+
+fn is_boundary(data: &[u8], i: usize) -> bool {
+    let is_letter = |c: Option<char>| c.is_some() && class(c) == Class::Letter;
+    let next = char_at(data, i);
+    if data[i] == b' ' {
+        return is_letter(char_at(data, i + 1));
+    }
+
+    // whitespace before the line feed would join it when the text ends there
+    if data[i - 1] == b'\n' {
+        return is_letter(next) && (i < 2 || class(char_before(data, i - 1)) != Class::Whitespace);
+    }
+
+    // chars cut off at the end of data are None, so they never allow a cut
+    is_letter(char_before(data, i)) && next.is_some() && class(next) == Class::Other
+}
+
+// the char starting at byte i, if it is complete and valid
+fn char_at(data: &[u8], i: usize) -> Option<char> {
+    let len = match *data.get(i)? {
+        0x00..0x80 => 1,
+        0xC0..0xE0 => 2,
+        0xE0..0xF0 => 3,
+        0xF0..0xF8 => 4,
+        _ => return None,
+    };
+    let bytes = data.get(i..i + len)?;
+    std::str::from_utf8(bytes).ok()?.chars().next()
+}
+
+// the char ending right before byte i, if it is complete and valid
+fn char_before(data: &[u8], i: usize) -> Option<char> {
+    let start = (i.saturating_sub(4)..i)
+        .rev()
+        .find(|j| !(0x80..0xC0).contains(&data[*j]))?;
+    char_at(data, start).filter(|c| start + c.len_utf8() == i)
+}
+
+// input decoded into unicode scalar values, invalid utf-8 bytes are kept as single `None` chars
+struct Text {
+    chars: Vec<(usize, Option<char>)>, // byte offset and char
+    end: usize,
+}
+
+impl Text {
+    fn new(data: &[u8]) -> Self {
+        let mut chars = Vec::with_capacity(data.len());
+        let mut offset = 0;
+        for chunk in data.utf8_chunks() {
+            for (i, c) in chunk.valid().char_indices() {
+                chars.push((offset + i, Some(c)));
+            }
+            offset += chunk.valid().len();
+
+            for _ in chunk.invalid() {
+                chars.push((offset, None));
+                offset += 1;
+            }
+        }
+
+        Self { chars, end: offset }
+    }
+
+    fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    fn offset(&self, i: usize) -> usize {
+        self.chars.get(i).map_or(self.end, |(offset, _)| *offset)
+    }
+
+    fn char(&self, i: usize) -> Option<char> {
+        self.chars.get(i).and_then(|(_, c)| *c)
+    }
+
+    fn class(&self, i: usize) -> Option<Class> {
+        (i < self.len()).then(|| class(self.char(i)))
+    }
+
+    fn run_while(&self, mut i: usize, f: impl Fn(Option<char>) -> bool) -> usize {
+        while i < self.len() && f(self.char(i)) {
+            i += 1;
+        }
+        i
+    }
+
+    fn run_of(&self, i: usize, target: Class) -> usize {
+        self.run_while(i, |c| class(c) == target)
+    }
+
+    // 's, 't, 're, 've, 'm, 'll, 'd
+    fn contraction(&self, i: usize, ignore_case: bool) -> Option<usize> {
+        if self.char(i) != Some('\'') {
+            return None;
+        }
+
+        let lower = |j: usize| {
+            self.char(j).map(|c| {
+                if ignore_case {
+                    c.to_ascii_lowercase()
+                } else {
+                    c
+                }
+            })
+        };
+        match (lower(i + 1), lower(i + 2)) {
+            (Some('r'), Some('e')) | (Some('v'), Some('e')) | (Some('l'), Some('l')) => Some(i + 3),
+            (Some('s' | 't' | 'm' | 'd'), _) => Some(i + 2),
+            _ => None,
+        }
+    }
+
+    // ` ?X+` for a character class X
+    fn prefixed_run(&self, i: usize, target: Class) -> Option<usize> {
+        let start = if self.char(i) == Some(' ') && self.class(i + 1) == Some(target) {
+            i + 1
+        } else {
+            i
+        };
+
+        if self.class(start) != Some(target) {
+            return None;
+        }
+        Some(self.run_of(start, target))
+    }
+
+    // `[^\r\n\p{L}\p{N}]?\p{L}+`
+    fn letters(&self, i: usize) -> Option<usize> {
+        let start = match self.class(i) {
+            Some(Class::Letter) => i,
+            Some(Class::Number) => return None,
+            Some(_) if !matches!(self.char(i), Some('\r' | '\n')) => i + 1,
+            _ => return None,
+        };
+
+        if self.class(start) != Some(Class::Letter) {
+            return None;
+        }
+        Some(self.run_of(start, Class::Letter))
+    }
+
+    // `\p{N}{1,3}`
+    fn digits(&self, i: usize) -> Option<usize> {
+        if self.class(i) != Some(Class::Number) {
+            return None;
+        }
+        Some(self.run_of(i, Class::Number).min(i + 3))
+    }
+
+    // `\s*[\r\n]+`, the whitespace run up to and including its last line break
+    fn newlines(&self, i: usize) -> Option<usize> {
+        let end = self.run_of(i, Class::Whitespace);
+        (i..end)
+            .rev()
+            .find(|j| matches!(self.char(*j), Some('\r' | '\n')))
+            .map(|j| j + 1)
+    }
+
+    // `\s+(?!\S)|\s+`, a whitespace run that leaves its last char to prefix a following word
+    fn whitespace(&self, i: usize) -> usize {
+        let end = self.run_of(i, Class::Whitespace);
+        if end == i {
+            return i + 1; // unreachable for complete patterns, but always make progress
+        }
+
+        if end < self.len() && end - i > 1 {
+            end - 1
+        } else {
+            end
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // splits of the reference patterns, from the python `regex` module
+    const GPT2_CASES: &[(&str, &[&str])] = &[
+        (
+            "Hello world, it's a test! We'll see 12345 things.",
+            &[
+                "Hello", " world", ",", " it", "'s", " a", " test", "!", " We", "'ll", " see",
+                " 12345", " things", ".",
+            ],
+        ),
+        (
+            "नमस्ते दुनिया",
+            &["नमस", "्", "त", "े", " द", "ु", "न", "ि", "य", "ा"],
+        ),
+        (
+            "Ⅻ chapters, ½ done, ²³ and ٣٤٥",
+            &[
+                "Ⅻ",
+                " chapters",
+                ",",
+                " ½",
+                " done",
+                ",",
+                " ²³",
+                " and",
+                " ٣٤٥",
+            ],
+        ),
+        (
+            "你好，世界。こんにちは",
+            &["你好", "，", "世界", "。", "こんにちは"],
+        ),
+        (
+            "fn main() {\n    let x = 1;\r\n\n}  ",
+            &[
+                "fn", " main", "()", " {", "\n   ", " let", " x", " =", " 1", ";", "\r\n", "\n",
+                "}", "  ",
+            ],
+        ),
+        (
+            "HE'S HERE'LL 'VE",
+            &["HE", "'", "S", " HERE", "'", "LL", " '", "VE"],
+        ),
+        (
+            "a b　c   d\t\te",
+            &["a", " ", "b", "　", "c", "  ", " d", "\t", "\t", "e"],
+        ),
+        ("naïve café ﬁ x́y", &["naïve", " café", " ﬁ", " x", "́", "y"]),
+        ("emoji 🎉🎉 ok!!", &["emoji", " 🎉🎉", " ok", "!!"]),
+    ];
+    const CL100K_CASES: &[(&str, &[&str])] = &[
+        (
+            "Hello world, it's a test! We'll see 12345 things.",
+            &[
+                "Hello", " world", ",", " it", "'s", " a", " test", "!", " We", "'ll", " see", " ",
+                "123", "45", " things", ".",
+            ],
+        ),
+        ("नमस्ते दुनिया", &["नमस", "्त", "े", " द", "ुन", "िय", "ा"]),
+        (
+            "Ⅻ chapters, ½ done, ²³ and ٣٤٥",
+            &[
+                "Ⅻ",
+                " chapters",
+                ",",
+                " ",
+                "½",
+                " done",
+                ",",
+                " ",
+                "²³",
+                " and",
+                " ",
+                "٣٤٥",
+            ],
+        ),
+        (
+            "你好，世界。こんにちは",
+            &["你好", "，世界", "。こんにちは"],
+        ),
+        (
+            "fn main() {\n    let x = 1;\r\n\n}  ",
+            &[
+                "fn", " main", "()", " {\n", "   ", " let", " x", " =", " ", "1", ";\r\n\n", "}",
+                "  ",
+            ],
+        ),
+        (
+            "HE'S HERE'LL 'VE",
+            &["HE", "'S", " HERE", "'LL", " '", "VE"],
+        ),
+        (
+            "a b　c   d\t\te",
+            &["a", " b", "　c", "  ", " d", "\t", "\te"],
+        ),
+        ("naïve café ﬁ x́y", &["naïve", " café", " ﬁ", " x", "́y"]),
+        ("emoji 🎉🎉 ok!!", &["emoji", " 🎉🎉", " ok", "!!"]),
+    ];
+
+    fn words<'a>(splitter: &dyn PreTokenizer, text: &'a str) -> Vec<&'a str> {
+        splitter
+            .split(text.as_bytes())
+            .into_iter()
+            .map(|(start, end)| &text[start..end])
+            .collect()
+    }
+
+    const PIECES: &[&str] = &[
+        "a", "b", "S", "ll", "é", "न", "्", "你", "好", "，", "。", "🎉", "Ⅻ", "1", "٣", " ", "  ",
+        "\n", "\r", "\t", "\u{a0}", "\u{3000}", "'", "'s", ",", "!", "<|", "\u{301}",
+    ];
+
+    fn text() -> impl Strategy<Value = Vec<u8>> {
+        let piece = prop_oneof![
+            9 => proptest::sample::select(PIECES).prop_map(|piece| piece.as_bytes().to_vec()),
+            1 => any::<u8>().prop_map(|b| vec![b]),
+        ];
+        proptest::collection::vec(piece, 0..30).prop_map(|pieces| pieces.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn boundaries_do_not_change_splits(data in text()) {
+            for kind in [PreTokenizerKind::Ascii, PreTokenizerKind::Gpt2, PreTokenizerKind::Cl100k] {
+                let splitter = kind.get();
+                let whole = splitter.split(&data);
+                // cuts found in any prefix, as a streaming reader sees them
+                for len in 0..=data.len() {
+                    let mut from = 0;
+                    loop {
+                        let cut = splitter.boundary(&data[..len], from);
+                        if cut >= len {
+                            break;
+                        }
+
+                        let mut parts = splitter.split(&data[..cut]);
+                        parts.extend(
+                            splitter
+                                .split(&data[cut..])
+                                .into_iter()
+                                .map(|(start, end)| (start + cut, end + cut)),
+                        );
+                        prop_assert_eq!(&parts, &whole, "{:?} cut at {} by {:?}", data, cut, kind);
+                        from = cut + 1;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gpt2_matches_the_pattern() {
+        for (text, expected) in GPT2_CASES.iter() {
+            assert_eq!(words(&Gpt2PreTokenizer, text), *expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn cl100k_matches_the_pattern() {
+        for (text, expected) in CL100K_CASES.iter() {
+            assert_eq!(words(&Cl100kPreTokenizer, text), *expected, "{:?}", text);
+        }
+    }
+}
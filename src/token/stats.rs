@@ -0,0 +1,247 @@
+use super::tokenizer::{BpeTokenizer, EncodeOptions};
+use std::{collections::BTreeMap, fmt};
+
+/// Writing systems that text is grouped by in a `TokenizerReport`, by Unicode block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Script {
+    Common, // ascii digits, punctuation and whitespace, general punctuation
+    Latin,
+    Greek,
+    Cyrillic,
+    Hebrew,
+    Arabic,
+    Devanagari,
+    Thai,
+    Hangul,
+    Kana,
+    Han,
+    Other, // any other char, and invalid utf-8
+}
+
+impl Script {
+    pub fn of(c: char) -> Self {
+        match c as u32 {
+            0x41..=0x5A | 0x61..=0x7A => Script::Latin,
+            0x00..=0x7F | 0xA0..=0xBF | 0x2000..=0x206F | 0x3000..=0x303F => Script::Common,
+            0xC0..=0x24F | 0x1E00..=0x1EFF => Script::Latin,
+            0x370..=0x3FF | 0x1F00..=0x1FFF => Script::Greek,
+            0x400..=0x52F => Script::Cyrillic,
+            0x590..=0x5FF => Script::Hebrew,
+            0x600..=0x6FF | 0x750..=0x77F => Script::Arabic,
+            0x900..=0x97F => Script::Devanagari,
+            0xE00..=0xE7F => Script::Thai,
+            0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => Script::Hangul,
+            0x3040..=0x30FF => Script::Kana,
+            0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0x20000..=0x2FA1F => Script::Han,
+            _ => Script::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScriptStats {
+    pub bytes: usize,
+    pub tokens: usize,
+    pub byte_fallback_tokens: usize,
+}
+
+impl ScriptStats {
+    pub fn bytes_per_token(&self) -> f64 {
+        self.bytes as f64 / self.tokens.max(1) as f64
+    }
+}
+
+/// How a tokenizer splits a corpus. Tokens are counted without the eos that `encode` appends. A
+/// byte fallback token is one that cuts through a UTF-8 character or covers invalid bytes, so it
+/// does not stand for whole characters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenizerReport {
+    pub bytes: usize,
+    pub tokens: usize,
+    pub byte_fallback_tokens: usize,
+    pub length_histogram: Vec<usize>, // number of tokens covering each byte length
+    pub counts: Vec<u64>,             // occurrences of each token id
+    pub unused: Vec<u32>,             // regular tokens that never occur
+    pub scripts: BTreeMap<Script, ScriptStats>,
+}
+
+impl TokenizerReport {
+    pub fn bytes_per_token(&self) -> f64 {
+        self.bytes as f64 / self.tokens.max(1) as f64
+    }
+
+    pub fn byte_fallback_fraction(&self) -> f64 {
+        self.byte_fallback_tokens as f64 / self.tokens.max(1) as f64
+    }
+
+    /// Tokens that occur at least once but at most `max_count` times, rarest first.
+    pub fn rare(&self, max_count: u64) -> Vec<(u32, u64)> {
+        let mut rare: Vec<(u32, u64)> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| (1..=max_count).contains(*count))
+            .map(|(id, count)| (id as u32, *count))
+            .collect();
+        rare.sort_by_key(|(id, count)| (*count, *id));
+        rare
+    }
+}
+
+impl fmt::Display for TokenizerReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "bytes: {}", self.bytes)?;
+        writeln!(f, "tokens: {}", self.tokens)?;
+        writeln!(f, "bytes per token: {:.3}", self.bytes_per_token())?;
+        writeln!(
+            f,
+            "byte fallback tokens: {} ({:.2}%)",
+            self.byte_fallback_tokens,
+            100.0 * self.byte_fallback_fraction()
+        )?;
+        writeln!(f, "unused tokens: {}", self.unused.len())?;
+
+        writeln!(f, "token lengths:")?;
+        for (len, count) in self.length_histogram.iter().enumerate() {
+            if *count > 0 {
+                writeln!(f, "  {:>4}  {}", len, count)?;
+            }
+        }
+
+        writeln!(f, "scripts:")?;
+        for (script, stats) in self.scripts.iter() {
+            writeln!(
+                f,
+                "  {:<10}  {:>10} bytes  {:>10} tokens  {:.3} bytes/token  {} byte fallback",
+                format!("{:?}", script),
+                stats.bytes,
+                stats.tokens,
+                stats.bytes_per_token(),
+                stats.byte_fallback_tokens
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl BpeTokenizer {
+    /// Encodes `corpus` and reports compression, token usage and coverage per script.
+    pub fn analyze(&self, corpus: &[u8]) -> Result<TokenizerReport, String> {
+        // special token strings count as the text they are
+        let options = EncodeOptions {
+            add_eos: false,
+            ..EncodeOptions::default()
+        };
+        let offsets = self.encode_with_offsets(corpus, &options)?;
+
+        // script of the char each byte belongs to
+        let mut scripts = vec![Script::Other; corpus.len()];
+        let mut offset = 0;
+        for chunk in corpus.utf8_chunks() {
+            for (i, c) in chunk.valid().char_indices() {
+                scripts[offset + i..offset + i + c.len_utf8()].fill(Script::of(c));
+            }
+            offset += chunk.valid().len() + chunk.invalid().len();
+        }
+
+        let mut report = TokenizerReport {
+            bytes: corpus.len(),
+            tokens: offsets.len(),
+            byte_fallback_tokens: 0,
+            length_histogram: Vec::new(),
+            counts: vec![0; self.vocab_size()],
+            unused: Vec::new(),
+            scripts: BTreeMap::new(),
+        };
+
+        for script in scripts.iter() {
+            report.scripts.entry(*script).or_default().bytes += 1;
+        }
+
+        for (id, span) in offsets.iter() {
+            report.counts[*id as usize] += 1;
+            let Some((start, end)) = *span else {
+                continue;
+            };
+
+            let len = end - start;
+            if report.length_histogram.len() <= len {
+                report.length_histogram.resize(len + 1, 0);
+            }
+            report.length_histogram[len] += 1;
+
+            // a token belongs to the first script in it that is not common, e.g. " hello" is latin
+            let script = scripts[start..end]
+                .iter()
+                .copied()
+                .find(|script| *script != Script::Common)
+                .unwrap_or(Script::Common);
+            let fallback = std::str::from_utf8(&corpus[start..end]).is_err();
+
+            let stats = report.scripts.entry(script).or_default();
+            stats.tokens += 1;
+            if fallback {
+                stats.byte_fallback_tokens += 1;
+                report.byte_fallback_tokens += 1;
+            }
+        }
+
+        let specials: Vec<u32> = self.special_tokens().iter().map(|(_, id)| *id).collect();
+        report.unused = (0..self.vocab_size() as u32)
+            .filter(|id| report.counts[*id as usize] == 0 && !specials.contains(id))
+            .filter(|id| self.token_bytes(*id).is_some_and(|bytes| !bytes.is_empty()))
+            .collect();
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::pretokenizer::PreTokenizerKind;
+    use crate::token::tokenizer::{SpecialToken, UnknownPolicy};
+
+    #[test]
+    fn report_on_a_hand_counted_corpus() {
+        // single bytes plus "ab" and the first two bytes of 東, which are no whole char
+        let mut vocab: Vec<(Vec<u8>, u32)> = (0..=255).map(|b| (vec![b], b as u32)).collect();
+        vocab.push((b"ab".to_vec(), 256));
+        vocab.push((vec![0xE6, 0x9D], 257));
+        let merges = vec![(b"a".to_vec(), b"b".to_vec()), (vec![0xE6], vec![0x9D])];
+        let tokenizer = BpeTokenizer::from_vocab(
+            vocab,
+            merges,
+            vec![(SpecialToken::Eos, 258)],
+            PreTokenizerKind::Gpt2,
+            UnknownPolicy::ByteFallback,
+        )
+        .unwrap();
+
+        // words "ab", " é東", "<|", "eos", "|>" give the tokens
+        // ab | ' ' c3 a9 e69d b1 | < | | e o s | | >
+        let report = tokenizer.analyze("ab é東<|eos|>".as_bytes()).unwrap();
+        assert_eq!(report.bytes, 15);
+        assert_eq!(report.tokens, 13);
+        assert_eq!(report.bytes_per_token(), 15.0 / 13.0);
+        assert_eq!(report.length_histogram, [0, 11, 2]);
+        assert_eq!(report.byte_fallback_tokens, 4);
+        assert_eq!(report.counts[256], 1);
+        assert_eq!(report.counts[257], 1);
+        assert_eq!(report.counts[b'|' as usize], 2);
+        assert_eq!(report.counts[258], 0);
+        assert_eq!(report.unused.len(), 258 - 12);
+
+        let stats = |bytes, tokens, byte_fallback_tokens| ScriptStats {
+            bytes,
+            tokens,
+            byte_fallback_tokens,
+        };
+        let scripts = BTreeMap::from([
+            (Script::Common, stats(5, 5, 0)),
+            (Script::Latin, stats(7, 6, 2)),
+            (Script::Han, stats(3, 2, 2)),
+        ]);
+        assert_eq!(report.scripts, scripts);
+    }
+}
@@ -1,15 +1,71 @@
+use super::cache::LruCache;
+use super::pretokenizer::PreTokenizerKind;
+use super::train::{self, MergeQueue, WordCounter};
 use super::utils;
+use rand::Rng;
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{BufReader, BufWriter, Read, Write},
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fs::{self, File},
+    io::{BufWriter, Read, Write},
+    sync::Mutex,
+    thread,
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+const STREAM_BLOCK_SIZE: usize = 1 << 20;
+const ENCODE_CACHE_SIZE: usize = 1 << 16; // words
+
+const MAGIC: &[u8; 8] = b"GPTRSBPE";
+const FORMAT_VERSION: u32 = 4; // 2: named special tokens, 3: byte ids, 4: unknown policy
+const NAMED_SPECIAL_TAG: u8 = 255;
+
+const SECTION_CONFIG: u32 = 1;
+const SECTION_VOCAB: u32 = 2;
+const SECTION_SPECIALS: u32 = 3;
+const SECTION_MERGES: u32 = 4;
+const SECTION_PAIRS: u32 = 5;
+const SECTION_BYTE_IDS: u32 = 6; // optional, raw bytes map to ids 0..256 without it
+const MISSING_BYTE_ID: u32 = u32::MAX; // a byte without a token in the byte ids section
+
+// token pairs, each mapped to a rank or to the merged token
+type PairTable = Vec<((u32, u32), u32)>;
+
+/// Tokens paired with the byte range of the input they cover, if any.
+pub type TokenOffsets = Vec<(u32, Option<(usize, usize)>)>;
+
+// encoded tokens of recently seen words
+type WordCache = LruCache<Box<[u8]>, Box<[u32]>>;
+
+/// Shared interface of the tokenization models, so training code can swap between them.
+pub trait Tokenizer {
+    /// Encodes raw bytes, appending eos if the tokenizer has one.
+    fn encode(&self, data: &[u8]) -> Result<Vec<u32>, String>;
+
+    /// Encodes raw bytes as plain text, without special tokens of any kind.
+    fn encode_ordinary(&self, data: &[u8]) -> Result<Vec<u32>, String>;
+
+    /// Decodes tokens back into the bytes they were encoded from, skipping special tokens.
+    fn decode(&self, tokens: &[u32]) -> Result<Vec<u8>, String>;
+
+    /// Number of token ids, special tokens included.
+    fn vocab_size(&self) -> usize;
+
+    fn special_token_id(&self, token: &SpecialToken) -> Option<u32>;
+
+    fn save(&self, path: &str) -> Result<(), String>;
+
+    fn load(path: &str) -> Result<Self, String>
+    where
+        Self: Sized;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum SpecialToken {
     Eos,
     Unk,
     Eow,
+    Bos,
+    Named(String), // user-defined, e.g. `<|im_start|>`, whose repr is the name itself
 }
 
 impl SpecialToken {
@@ -18,14 +74,22 @@ impl SpecialToken {
             SpecialToken::Eos => b"<|eos|>".to_vec(),
             SpecialToken::Unk => b"<|unk|>".to_vec(),
             SpecialToken::Eow => b"<|eow|>".to_vec(),
+            SpecialToken::Bos => b"<|bos|>".to_vec(),
+            SpecialToken::Named(name) => name.as_bytes().to_vec(),
         }
     }
 
+    pub fn named(name: &str) -> Self {
+        SpecialToken::Named(name.to_string())
+    }
+
     pub fn to_u8(&self) -> u8 {
         match self {
             SpecialToken::Eos => 0,
             SpecialToken::Unk => 1,
             SpecialToken::Eow => 2,
+            SpecialToken::Bos => 3,
+            SpecialToken::Named(_) => NAMED_SPECIAL_TAG,
         }
     }
 
@@ -34,14 +98,130 @@ impl SpecialToken {
             0 => Ok(SpecialToken::Eos),
             1 => Ok(SpecialToken::Unk),
             2 => Ok(SpecialToken::Eow),
+            3 => Ok(SpecialToken::Bos),
+            NAMED_SPECIAL_TAG => Err("Named special token is missing its name".to_string()),
             _ => Err("Invalid special token byte".to_string()),
         }
     }
+
+    /// Writes the numeric tag, followed by the name for named tokens.
+    fn write(&self, writer: &mut dyn Write) -> Result<(), String> {
+        writer
+            .write_all(&[self.to_u8()])
+            .map_err(|e| e.to_string())?;
+        if let SpecialToken::Named(name) = self {
+            utils::write_u32(writer, name.len() as u32)?;
+            writer
+                .write_all(name.as_bytes())
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn read(reader: &mut dyn Read) -> Result<Self, String> {
+        let mut buf = [0_u8; 1];
+        reader.read_exact(&mut buf).map_err(|e| e.to_string())?;
+        if buf[0] != NAMED_SPECIAL_TAG {
+            return Self::from_u8(buf[0]);
+        }
+
+        let len = utils::read_u32(reader)? as usize;
+        let mut name = vec![0_u8; len];
+        reader.read_exact(&mut name).map_err(|e| e.to_string())?;
+        String::from_utf8(name)
+            .map(SpecialToken::Named)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// A set of special tokens, used to pick which literal special token strings `encode` looks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpecialSet {
+    None,
+    All,
+    Only(Vec<SpecialToken>),
+}
+
+impl SpecialSet {
+    pub fn contains(&self, token: &SpecialToken) -> bool {
+        match self {
+            SpecialSet::None => false,
+            SpecialSet::All => true,
+            SpecialSet::Only(tokens) => tokens.contains(token),
+        }
+    }
+}
+
+/// Controls special tokens during encoding. Literal special token strings in the input follow
+/// tiktoken's rules: allowed ones become the special token, disallowed ones that are not also
+/// allowed make encoding fail, and all others are encoded as plain text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodeOptions {
+    pub add_bos: bool, // prepend bos if the tokenizer has one
+    pub add_eos: bool, // append eos if the tokenizer has one
+    pub allowed_special: SpecialSet,
+    pub disallowed_special: SpecialSet,
+}
+
+impl Default for EncodeOptions {
+    /// Appends eos and encodes special token strings in the input as text, so any input encodes,
+    /// including the corpus the tokenizer was trained on. tiktoken rejects them by default, set
+    /// `disallowed_special` to `SpecialSet::All` for that behavior.
+    fn default() -> Self {
+        Self {
+            add_bos: false,
+            add_eos: true,
+            allowed_special: SpecialSet::None,
+            disallowed_special: SpecialSet::None,
+        }
+    }
+}
+
+/// Controls how `decode` treats special tokens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeOptions {
+    pub skip_special: bool, // drop special tokens and eow markers, otherwise render their reprs
+}
+
+impl Default for DecodeOptions {
+    /// Skips special tokens, so the output is the text that was encoded.
+    fn default() -> Self {
+        Self { skip_special: true }
+    }
+}
+
+/// What happens to input bytes and token ids that have no entry in the vocabulary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnknownPolicy {
+    ByteFallback, // every byte has a token, unknown ids decode to U+FFFD
+    Unk,          // unknown bytes and ids become the unk special token
+    Strict,       // unknown ids are an error, and so is a vocabulary missing a byte
+}
+
+impl UnknownPolicy {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            UnknownPolicy::ByteFallback => 0,
+            UnknownPolicy::Unk => 1,
+            UnknownPolicy::Strict => 2,
+        }
+    }
+
+    pub fn from_u8(byte: u8) -> Result<Self, String> {
+        match byte {
+            0 => Ok(UnknownPolicy::ByteFallback),
+            1 => Ok(UnknownPolicy::Unk),
+            2 => Ok(UnknownPolicy::Strict),
+            _ => Err("Invalid unknown policy byte".to_string()),
+        }
+    }
 }
 
 pub struct BpeConfig {
     pub vocab_size: usize,
     pub special_tokens: Vec<SpecialToken>,
+    pub pre_tokenizer: PreTokenizerKind,
+    pub unknown_policy: UnknownPolicy,
 }
 
 impl BpeConfig {
@@ -49,18 +229,22 @@ impl BpeConfig {
         Self {
             vocab_size: 50257,
             special_tokens: vec![SpecialToken::Eos],
+            pre_tokenizer: PreTokenizerKind::Gpt2,
+            unknown_policy: UnknownPolicy::ByteFallback,
         }
     }
 }
 
 pub struct BpeTokenizer {
-    i2t: Vec<Box<[u8]>>,          // id to raw token byte array
-    t2i: HashMap<Box<[u8]>, u32>, // raw token byte array to id
-    config: BpeConfig,
-    special_tokens: HashMap<SpecialToken, u32>, // map special token to id
-    built: bool,
-    merge_rank: HashMap<(u32, u32), usize>, // stores the rank of the best pair at which we choose to merge two tokens
-    pair_to_token: HashMap<(u32, u32), u32>, // stores the resulting merged token for a given pair
+    pub(super) i2t: Vec<Box<[u8]>>,        // id to raw token byte array
+    t2i: HashMap<Box<[u8]>, u32>,          // raw token byte array to id
+    pub(super) byte_ids: Vec<Option<u32>>, // id of each of the 256 single byte tokens
+    pub(super) config: BpeConfig,
+    pub(super) special_tokens: HashMap<SpecialToken, u32>, // map special token to id
+    pub(super) built: bool,
+    pub(super) merge_rank: HashMap<(u32, u32), usize>, // stores the rank of the best pair at which we choose to merge two tokens
+    pub(super) pair_to_token: HashMap<(u32, u32), u32>, // stores the resulting merged token for a given pair
+    cache: Mutex<WordCache>,
 }
 
 impl BpeTokenizer {
@@ -69,14 +253,38 @@ impl BpeTokenizer {
             return Err("Please use a vocabulary size of at least 256".to_string());
         }
 
+        for (i, token) in config.special_tokens.iter().enumerate() {
+            if token.repr().is_empty() {
+                return Err("Special token names must not be empty".to_string());
+            }
+
+            if config.special_tokens[..i]
+                .iter()
+                .any(|other| other.repr() == token.repr())
+            {
+                return Err(format!(
+                    "Special token {} is registered twice",
+                    String::from_utf8_lossy(&token.repr())
+                ));
+            }
+        }
+
+        if config.unknown_policy == UnknownPolicy::Unk
+            && !config.special_tokens.contains(&SpecialToken::Unk)
+        {
+            return Err("The unk policy needs the unk special token".to_string());
+        }
+
         Ok(Self {
             i2t: Vec::new(),
             t2i: HashMap::new(),
+            byte_ids: (0..256).map(Some).collect(),
             config,
             special_tokens: HashMap::new(),
             built: false,
             merge_rank: HashMap::new(),
             pair_to_token: HashMap::new(),
+            cache: Mutex::new(LruCache::new(ENCODE_CACHE_SIZE)),
         })
     }
 
@@ -85,144 +293,429 @@ impl BpeTokenizer {
             return Err("Tokenizer not built yet".to_string());
         }
 
-        let file = File::create(path).map_err(|e| e.to_string())?;
-        let mut writer = BufWriter::new(&file);
-
-        utils::write_u32(&mut writer, self.config.special_tokens.len() as u32)?;
-        utils::write_u32(&mut writer, self.config.vocab_size as u32)?;
-        for token in self.config.special_tokens.iter() {
-            writer
-                .write_all(&[token.to_u8()]) // saves the special tokens as a numeric tag
-                .map_err(|e| e.to_string())?;
-        }
+        let mut config = Vec::new();
+        utils::write_u32(&mut config, self.config.vocab_size as u32)?;
+        config.push(self.config.pre_tokenizer.to_u8()?);
+        write_special_tags(&mut config, &self.config.special_tokens)?;
+        config.push(self.config.unknown_policy.to_u8());
 
-        utils::write_u32(&mut writer, self.i2t.len() as u32)?;
+        let mut vocab = Vec::new();
+        utils::write_u32(&mut vocab, self.i2t.len() as u32)?;
         for token_bytes in self.i2t.iter() {
-            utils::write_u32(&mut writer, token_bytes.len() as u32)?;
-            writer.write_all(token_bytes).map_err(|e| e.to_string())?;
+            utils::write_u32(&mut vocab, token_bytes.len() as u32)?;
+            vocab.extend_from_slice(token_bytes);
         }
 
-        let mut specials: Vec<(SpecialToken, u32)> =
-            self.special_tokens.iter().map(|(k, v)| (*k, *v)).collect();
-        specials.sort_by_key(|(token, _)| *token as u8);
-        utils::write_u32(&mut writer, specials.len() as u32)?;
-        for (token, id) in specials.iter() {
-            writer
-                .write_all(&[token.to_u8()])
-                .map_err(|e| e.to_string())?;
-            utils::write_u32(&mut writer, *id)?;
-        }
+        let mut special_ids = Vec::new();
+        write_special_ids(&mut special_ids, &self.special_tokens)?;
 
-        let mut merge_rank: Vec<((u32, u32), usize)> =
-            self.merge_rank.iter().map(|(k, v)| (*k, *v)).collect();
+        let mut merge_rank: Vec<((u32, u32), u32)> = self
+            .merge_rank
+            .iter()
+            .map(|(k, v)| (*k, *v as u32))
+            .collect();
         merge_rank.sort_by_key(|(_, rank)| *rank);
-        utils::write_u32(&mut writer, merge_rank.len() as u32)?;
-        for (pair, rank) in merge_rank.iter() {
-            let (a_id, b_id) = pair;
-            utils::write_u32(&mut writer, *a_id)?;
-            utils::write_u32(&mut writer, *b_id)?;
-            utils::write_u32(&mut writer, *rank as u32)?;
-        }
+        let mut merges = Vec::new();
+        write_pair_table(&mut merges, &merge_rank)?;
 
         let mut pair_to_token: Vec<((u32, u32), u32)> =
             self.pair_to_token.iter().map(|(k, v)| (*k, *v)).collect();
         pair_to_token.sort_by_key(|(_, token)| *token);
-        utils::write_u32(&mut writer, pair_to_token.len() as u32)?;
-        for (pair, token) in pair_to_token.iter() {
-            let (a_id, b_id) = pair;
-            utils::write_u32(&mut writer, *a_id)?;
-            utils::write_u32(&mut writer, *b_id)?;
-            utils::write_u32(&mut writer, *token)?;
+        let mut pairs = Vec::new();
+        write_pair_table(&mut pairs, &pair_to_token)?;
+
+        let mut byte_ids = Vec::new();
+        for id in self.byte_ids.iter() {
+            utils::write_u32(&mut byte_ids, id.unwrap_or(MISSING_BYTE_ID))?;
+        }
+
+        let sections = [
+            (SECTION_CONFIG, config),
+            (SECTION_VOCAB, vocab),
+            (SECTION_SPECIALS, special_ids),
+            (SECTION_MERGES, merges),
+            (SECTION_PAIRS, pairs),
+            (SECTION_BYTE_IDS, byte_ids),
+        ];
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        utils::write_u32(&mut out, FORMAT_VERSION)?;
+        utils::write_u32(&mut out, sections.len() as u32)?;
+        for (tag, payload) in sections.iter() {
+            utils::write_u32(&mut out, *tag)?;
+            utils::write_u64(&mut out, payload.len() as u64)?;
+            out.extend_from_slice(payload);
         }
+        let checksum = utils::crc32(&out);
+        utils::write_u32(&mut out, checksum)?;
 
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        let mut writer = BufWriter::new(&file);
+        writer.write_all(&out).map_err(|e| e.to_string())?;
         writer.flush().map_err(|e| e.to_string())?;
 
         Ok(())
     }
 
+    /// Loads a tokenizer saved by `save_to_binary`. Files from before the format was versioned are
+    /// still accepted, and saving them again upgrades them to the current format.
     pub fn load_from_binary(path: &str) -> Result<Self, String> {
-        let file = File::open(path).map_err(|e| e.to_string())?;
-        let mut reader = BufReader::new(&file);
+        let bytes = fs::read(path).map_err(|e| e.to_string())?;
+        if bytes.starts_with(MAGIC) {
+            Self::load_versioned(&bytes)
+        } else {
+            Self::load_legacy(&mut bytes.as_slice())
+                .map_err(|e| format!("{} is not a tokenizer file: {}", path, e))
+        }
+    }
+
+    fn load_versioned(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < MAGIC.len() + 12 {
+            return Err("Tokenizer file is truncated".to_string());
+        }
+
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        if utils::crc32(body) != utils::read_u32(&mut &checksum[..])? {
+            return Err("Tokenizer file checksum mismatch".to_string());
+        }
+
+        let mut reader = &body[MAGIC.len()..];
+        let version = utils::read_u32(&mut reader)?;
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(format!("Unsupported tokenizer format version {}", version));
+        }
+
+        // unknown sections are skipped so newer writers can add optional data
+        let mut sections: HashMap<u32, &[u8]> = HashMap::new();
+        let section_count = utils::read_u32(&mut reader)?;
+        for _ in 0..section_count {
+            let tag = utils::read_u32(&mut reader)?;
+            let len = utils::read_u64(&mut reader)? as usize;
+            if len > reader.len() {
+                return Err(format!("Tokenizer section {} is truncated", tag));
+            }
+            let (payload, rest) = reader.split_at(len);
+            sections.insert(tag, payload);
+            reader = rest;
+        }
+
+        if !reader.is_empty() {
+            return Err("Tokenizer file has trailing data".to_string());
+        }
+
+        let section = |tag: u32| -> Result<&[u8], String> {
+            sections
+                .get(&tag)
+                .copied()
+                .ok_or_else(|| format!("Tokenizer file is missing section {}", tag))
+        };
 
-        let vocab_size = utils::read_u32(&mut reader)?;
-        let special_count = utils::read_u32(&mut reader)?;
-        let mut config_specials = Vec::with_capacity(special_count as usize);
+        let mut config = section(SECTION_CONFIG)?;
+        let vocab_size = utils::read_u32(&mut config)?;
+        let mut buf = [0_u8; 1];
+        config.read_exact(&mut buf).map_err(|e| e.to_string())?;
+        let pre_tokenizer = PreTokenizerKind::from_u8(buf[0])?;
+        let config_specials = read_special_tags(&mut config)?;
+        let unknown_policy = match version {
+            4.. => {
+                config.read_exact(&mut buf).map_err(|e| e.to_string())?;
+                UnknownPolicy::from_u8(buf[0])?
+            }
+            _ => UnknownPolicy::ByteFallback,
+        };
+
+        let mut byte_ids: Vec<Option<u32>> = (0..256).map(Some).collect();
+        if let Some(mut section) = sections.get(&SECTION_BYTE_IDS).copied() {
+            for id in byte_ids.iter_mut() {
+                *id = Some(utils::read_u32(&mut section)?).filter(|id| *id != MISSING_BYTE_ID);
+            }
+        }
+
+        let tokenizer = Self::from_parts(
+            BpeConfig {
+                vocab_size: vocab_size as usize,
+                special_tokens: config_specials,
+                pre_tokenizer,
+                unknown_policy,
+            },
+            read_vocab(&mut section(SECTION_VOCAB)?)?,
+            read_special_ids(&mut section(SECTION_SPECIALS)?)?,
+            read_pair_table(&mut section(SECTION_MERGES)?)?
+                .into_iter()
+                .map(|(pair, rank)| (pair, rank as usize))
+                .collect(),
+            read_pair_table(&mut section(SECTION_PAIRS)?)?
+                .into_iter()
+                .collect(),
+            byte_ids,
+        );
+        tokenizer.check_tables()?;
+        Ok(tokenizer)
+    }
+
+    // reads the unversioned layout: special count, vocab size, config specials, vocab, special ids,
+    // merges, pairs and an optional trailing pre-tokenizer tag
+    fn load_legacy(reader: &mut dyn Read) -> Result<Self, String> {
+        let special_count = utils::read_u32(reader)?;
+        let vocab_size = utils::read_u32(reader)?;
+        let mut config_specials = Vec::new();
         for _ in 0..special_count {
             let mut buf = [0_u8; 1];
             reader.read_exact(&mut buf).map_err(|e| e.to_string())?;
             config_specials.push(SpecialToken::from_u8(buf[0])?);
         }
 
-        let vocab_len = utils::read_u32(&mut reader)?;
-        let mut i2t = Vec::with_capacity(vocab_len as usize);
-        for _ in 0..vocab_len {
-            let token_bytes_len = utils::read_u32(&mut reader)? as usize;
-            let mut token_bytes = vec![0_u8; token_bytes_len];
-            reader
-                .read_exact(&mut token_bytes)
-                .map_err(|e| e.to_string())?;
-            i2t.push(token_bytes.into_boxed_slice());
+        let i2t = read_vocab(reader)?;
+        let special_tokens = read_special_ids(reader)?;
+        let merge_rank = read_pair_table(reader)?
+            .into_iter()
+            .map(|(pair, rank)| (pair, rank as usize))
+            .collect();
+        let pair_to_token = read_pair_table(reader)?.into_iter().collect();
+
+        // files written before pre-tokenizers were configurable end here and used the ascii splitter
+        let mut buf = [0_u8; 1];
+        let pre_tokenizer = match reader.read(&mut buf).map_err(|e| e.to_string())? {
+            0 => PreTokenizerKind::Ascii,
+            _ => PreTokenizerKind::from_u8(buf[0])?,
+        };
+        if reader.read(&mut buf).map_err(|e| e.to_string())? != 0 {
+            return Err("Tokenizer file has trailing data".to_string());
         }
 
-        let mut special_tokens = HashMap::new();
-        let stored_special_count = utils::read_u32(&mut reader)?;
-        for _ in 0..stored_special_count {
-            let mut buf = [0_u8; 1];
-            reader.read_exact(&mut buf).map_err(|e| e.to_string())?;
-            let token = SpecialToken::from_u8(buf[0])?;
-            let id = utils::read_u32(&mut reader)?;
-            special_tokens.insert(token, id);
+        let tokenizer = Self::from_parts(
+            BpeConfig {
+                vocab_size: vocab_size as usize,
+                special_tokens: config_specials,
+                pre_tokenizer,
+                unknown_policy: UnknownPolicy::ByteFallback,
+            },
+            i2t,
+            special_tokens,
+            merge_rank,
+            pair_to_token,
+            (0..256).map(Some).collect(),
+        );
+        tokenizer.check_tables()?;
+        Ok(tokenizer)
+    }
+
+    // rejects tables that do not fit together, as read from a damaged or foreign file
+    fn check_tables(&self) -> Result<(), String> {
+        let len = self.i2t.len() as u32;
+        let invalid = |what: &str| Err(format!("Tokenizer file has an invalid {}", what));
+
+        if self.special_tokens.values().any(|id| *id >= len)
+            || self
+                .config
+                .special_tokens
+                .iter()
+                .any(|token| !self.special_tokens.contains_key(token))
+        {
+            return invalid("special token table");
         }
 
-        let mut merge_rank = HashMap::new();
-        let merge_count = utils::read_u32(&mut reader)?;
-        for _ in 0..merge_count {
-            let a = utils::read_u32(&mut reader)?;
-            let b = utils::read_u32(&mut reader)?;
-            let rank = utils::read_u32(&mut reader)?;
-            merge_rank.insert((a, b), rank as usize);
+        if self.byte_ids.len() != 256
+            || self
+                .byte_ids
+                .iter()
+                .enumerate()
+                .any(|(b, id)| id.is_some_and(|id| self.token_bytes(id) != Some(&[b as u8][..])))
+        {
+            return invalid("byte table");
         }
 
-        let mut pair_to_token = HashMap::new();
-        let pair_count = utils::read_u32(&mut reader)?;
-        for _ in 0..pair_count {
-            let a = utils::read_u32(&mut reader)?;
-            let b = utils::read_u32(&mut reader)?;
-            let merged = utils::read_u32(&mut reader)?;
-            pair_to_token.insert((a, b), merged);
+        // only unk can stand in for bytes without a token
+        match self.config.unknown_policy {
+            UnknownPolicy::Unk if !self.special_tokens.contains_key(&SpecialToken::Unk) => {
+                return invalid("unknown policy without an unk token");
+            }
+            UnknownPolicy::ByteFallback | UnknownPolicy::Strict
+                if self.byte_ids.iter().any(Option::is_none) =>
+            {
+                return invalid("byte table for its unknown policy");
+            }
+            _ => {}
         }
 
+        for ((a, b), merged) in self.pair_to_token.iter() {
+            if *a >= len || *b >= len || *merged >= len {
+                return invalid("merge table");
+            }
+
+            let bytes = [
+                self.i2t[*a as usize].as_ref(),
+                self.i2t[*b as usize].as_ref(),
+            ]
+            .concat();
+            if *self.i2t[*merged as usize] != *bytes {
+                return invalid("merge table");
+            }
+        }
+
+        if self
+            .merge_rank
+            .keys()
+            .any(|pair| !self.pair_to_token.contains_key(pair))
+        {
+            return invalid("merge table");
+        }
+        Ok(())
+    }
+
+    /// Assembles a built tokenizer from its tables, e.g. for vocabularies trained elsewhere.
+    pub(super) fn from_parts(
+        config: BpeConfig,
+        i2t: Vec<Box<[u8]>>,
+        special_tokens: HashMap<SpecialToken, u32>,
+        merge_rank: HashMap<(u32, u32), usize>,
+        pair_to_token: HashMap<(u32, u32), u32>,
+        byte_ids: Vec<Option<u32>>,
+    ) -> Self {
         let mut t2i = HashMap::with_capacity(i2t.len());
         for (i, token_bytes) in i2t.iter().enumerate() {
-            t2i.insert(token_bytes.clone(), i as u32);
+            if !token_bytes.is_empty() && !special_tokens.values().any(|id| *id == i as u32) {
+                t2i.insert(token_bytes.clone(), i as u32);
+            }
         }
 
-        Ok(Self {
+        Self {
             i2t,
             t2i,
-            config: BpeConfig {
-                vocab_size: vocab_size as usize,
-                special_tokens: config_specials,
-            },
+            byte_ids,
+            config,
             special_tokens,
             built: true,
             merge_rank,
             pair_to_token,
-        })
+            cache: Mutex::new(LruCache::new(ENCODE_CACHE_SIZE)),
+        }
+    }
+
+    pub fn special_token_id(&self, token: &SpecialToken) -> Option<u32> {
+        self.special_tokens.get(token).copied()
+    }
+
+    /// Special tokens with their ids, in id order.
+    pub fn special_tokens(&self) -> Vec<(SpecialToken, u32)> {
+        let mut specials: Vec<(SpecialToken, u32)> = self
+            .special_tokens
+            .iter()
+            .map(|(token, id)| (token.clone(), *id))
+            .collect();
+        specials.sort_by_key(|(_, id)| *id);
+        specials
+    }
+
+    /// Number of token ids, counting special tokens and unused ids.
+    pub fn vocab_size(&self) -> usize {
+        self.i2t.len()
+    }
+
+    pub fn merge_count(&self) -> usize {
+        self.merge_rank.len()
+    }
+
+    /// Raw bytes of a token, the repr for special tokens.
+    pub fn token_bytes(&self, id: u32) -> Option<&[u8]> {
+        self.i2t
+            .get(id as usize)
+            .map(|token_bytes| token_bytes.as_ref())
+    }
+
+    pub fn config(&self) -> &BpeConfig {
+        &self.config
+    }
+
+    /// Switches how unknown bytes and ids are handled. Byte fallback adds a token for every byte
+    /// the vocabulary is missing, the unk policy needs the unk special token, and the strict policy
+    /// needs a token for every byte.
+    pub fn set_unknown_policy(&mut self, policy: UnknownPolicy) -> Result<(), String> {
+        let previous = self.config.unknown_policy;
+        self.config.unknown_policy = policy;
+        if let Err(e) = self.apply_unknown_policy() {
+            self.config.unknown_policy = previous;
+            return Err(e);
+        }
+
+        self.cache
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        Ok(())
     }
 
+    // checks the vocabulary against the configured policy, adding byte tokens for byte fallback
+    pub(super) fn apply_unknown_policy(&mut self) -> Result<(), String> {
+        let missing: Vec<u8> = (0..=255_u8)
+            .filter(|b| self.byte_ids[*b as usize].is_none())
+            .collect();
+        match self.config.unknown_policy {
+            UnknownPolicy::ByteFallback => {
+                for b in missing {
+                    let id = self.i2t.len() as u32;
+                    self.i2t.push(Box::new([b]));
+                    self.t2i.insert(Box::new([b]), id);
+                    self.byte_ids[b as usize] = Some(id);
+                }
+                self.config.vocab_size = self.config.vocab_size.max(self.i2t.len());
+                Ok(())
+            }
+            UnknownPolicy::Unk if !self.special_tokens.contains_key(&SpecialToken::Unk) => {
+                Err("The unk policy needs the unk special token".to_string())
+            }
+            UnknownPolicy::Unk => Ok(()),
+            UnknownPolicy::Strict => match missing.first() {
+                Some(b) => Err(format!("Vocabulary has no token for byte {:#04x}", b)),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Decodes with the default options, which give back the original input of `encode` exactly.
     pub fn decode(&self, tokens: &[u32]) -> Result<Vec<u8>, String> {
+        self.decode_with_options(tokens, &DecodeOptions::default())
+    }
+
+    pub fn decode_with_options(
+        &self,
+        tokens: &[u32],
+        options: &DecodeOptions,
+    ) -> Result<Vec<u8>, String> {
         if !self.built {
             return Err("Tokenizer not built yet".to_string());
         }
 
+        let eow = self
+            .special_tokens
+            .contains_key(&SpecialToken::Eow)
+            .then(|| SpecialToken::Eow.repr());
+        let unk = SpecialToken::Unk.repr();
         let mut bytes: Vec<u8> = Vec::new();
 
         for token in tokens.iter() {
-            if let Some(token_bytes) = self.i2t.get(*token as usize) {
+            let token_bytes = match self.i2t.get(*token as usize) {
+                Some(token_bytes) if !token_bytes.is_empty() => token_bytes.as_ref(),
+                _ => match self.config.unknown_policy {
+                    UnknownPolicy::ByteFallback => "\u{FFFD}".as_bytes(),
+                    UnknownPolicy::Unk if options.skip_special => continue,
+                    UnknownPolicy::Unk => &unk,
+                    UnknownPolicy::Strict => return Err(format!("Unknown token id {}", token)),
+                },
+            };
+
+            if !options.skip_special {
                 bytes.extend(token_bytes.iter());
-            } else {
-                return Err("Token not found".to_string());
+            } else if !self.special_tokens.values().any(|id| id == token) {
+                // merged tokens carry the eow marker of the word they end
+                let text = match &eow {
+                    Some(eow) => token_bytes
+                        .strip_suffix(eow.as_slice())
+                        .unwrap_or(token_bytes),
+                    None => token_bytes,
+                };
+                bytes.extend_from_slice(text);
             }
         }
 
@@ -234,38 +727,305 @@ impl BpeTokenizer {
             return Err("Tokenizer not built yet".to_string());
         }
 
+        self.encode_with_options(data, &EncodeOptions::default())
+    }
+
+    pub fn encode_with_options(
+        &self,
+        data: &[u8],
+        options: &EncodeOptions,
+    ) -> Result<Vec<u32>, String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
         let mut tokens: Vec<u32> = Vec::new();
+        if options.add_bos
+            && let Some(&bos_id) = self.special_tokens.get(&SpecialToken::Bos)
+        {
+            tokens.push(bos_id);
+        }
 
-        let mut words: Vec<Vec<u32>> = Vec::new();
-        let mut cur: Vec<u32> = Vec::new();
-        for c in data.iter() {
-            if c.is_ascii_alphanumeric() {
-                cur.push(*c as u32);
-            } else {
-                if cur.len() > 0 {
-                    if let Some(&eow_id) = self.special_tokens.get(&SpecialToken::Eow) {
-                        cur.push(eow_id);
-                    }
-                    words.push(cur);
-                    cur = Vec::new();
-                }
+        for (start, end, special) in self.segments(data, options)? {
+            match special {
+                Some(id) => tokens.push(id),
+                None => tokens.extend(self.encode_text(&data[start..end])?),
+            }
+        }
+
+        if options.add_eos
+            && let Some(&eos_id) = self.special_tokens.get(&SpecialToken::Eos)
+        {
+            tokens.push(eos_id);
+        }
+
+        Ok(tokens)
+    }
+
+    /// Encodes like `encode_with_options` and pairs every token with the `(start, end)` byte range
+    /// of `data` it covers. Special tokens found in the input cover their literal string, tokens
+    /// without a source span, such as bos, eos or a token made of eow alone, get `None`.
+    pub fn encode_with_offsets(
+        &self,
+        data: &[u8],
+        options: &EncodeOptions,
+    ) -> Result<TokenOffsets, String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        let mut tokens = Vec::new();
+        if options.add_bos
+            && let Some(&bos_id) = self.special_tokens.get(&SpecialToken::Bos)
+        {
+            tokens.push((bos_id, None));
+        }
+
+        for (start, end, special) in self.segments(data, options)? {
+            if let Some(id) = special {
+                tokens.push((id, Some((start, end))));
+                continue;
+            }
 
-                cur.push(*c as u32);
-                if let Some(&eow_id) = self.special_tokens.get(&SpecialToken::Eow) {
-                    cur.push(eow_id);
+            for (word_start, word_end) in self.config.pre_tokenizer.get().split(&data[start..end]) {
+                let (word_start, word_end) = (start + word_start, start + word_end);
+                let merged = self.merge(&self.symbols(&data[word_start..word_end])?);
+                for (i, (token, first)) in merged.iter().enumerate() {
+                    // a token runs up to the first symbol of the next one, eow is not in the input
+                    let token_start = word_start + first;
+                    let token_end = merged
+                        .get(i + 1)
+                        .map_or(word_end, |(_, next)| word_start + next)
+                        .min(word_end);
+                    let span = (token_start < token_end).then_some((token_start, token_end));
+                    tokens.push((*token, span));
                 }
-                words.push(cur);
-                cur = Vec::new();
             }
         }
 
-        // clean up and add last word
-        if cur.len() > 0 {
-            if let Some(&eow_id) = self.special_tokens.get(&SpecialToken::Eow) {
-                cur.push(eow_id);
+        if options.add_eos
+            && let Some(&eos_id) = self.special_tokens.get(&SpecialToken::Eos)
+        {
+            tokens.push((eos_id, None));
+        }
+
+        Ok(tokens)
+    }
+
+    /// BPE-dropout: encodes like `encode` but skips every applicable merge with probability
+    /// `dropout`, so the same text gets varied segmentations. A dropout of 0.0 gives the `encode`
+    /// output and 1.0 leaves single bytes.
+    pub fn encode_with_dropout<R: Rng>(
+        &self,
+        data: &[u8],
+        dropout: f64,
+        rng: &mut R,
+    ) -> Result<Vec<u32>, String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        if !(0.0..=1.0).contains(&dropout) {
+            return Err(format!("Dropout {} is not between 0 and 1", dropout));
+        }
+
+        let options = EncodeOptions::default();
+        let mut tokens = Vec::new();
+        for (start, end, special) in self.segments(data, &options)? {
+            if let Some(id) = special {
+                tokens.push(id);
+                continue;
+            }
+
+            for (word_start, word_end) in self.config.pre_tokenizer.get().split(&data[start..end]) {
+                let symbols = self.symbols(&data[start + word_start..start + word_end])?;
+                let merged = self.merge_with(&symbols, || !rng.random_bool(dropout));
+                tokens.extend(merged.into_iter().map(|(token, _)| token));
+            }
+        }
+
+        if let Some(&eos_id) = self.special_tokens.get(&SpecialToken::Eos) {
+            tokens.push(eos_id);
+        }
+
+        Ok(tokens)
+    }
+
+    // cuts data into text ranges and the special token strings found by the options, which carry
+    // the id to emit
+    fn segments(
+        &self,
+        data: &[u8],
+        options: &EncodeOptions,
+    ) -> Result<Vec<(usize, usize, Option<u32>)>, String> {
+        // special token strings to look for, with the id to emit or None if they are disallowed
+        let mut matchers: Vec<(Vec<u8>, Option<u32>)> = Vec::new();
+        for (token, id) in self.special_tokens.iter() {
+            if options.allowed_special.contains(token) {
+                matchers.push((token.repr(), Some(*id)));
+            } else if options.disallowed_special.contains(token) {
+                matchers.push((token.repr(), None));
             }
-            words.push(cur);
         }
+        // prefer the longest match when one special string is a prefix of another
+        matchers.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(&b.0)));
+
+        let mut segments = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < data.len() && !matchers.is_empty() {
+            let Some((repr, id)) = matchers
+                .iter()
+                .find(|(repr, _)| data[i..].starts_with(repr))
+            else {
+                i += 1;
+                continue;
+            };
+
+            let Some(id) = id else {
+                return Err(format!(
+                    "Disallowed special token {} found in input",
+                    String::from_utf8_lossy(repr)
+                ));
+            };
+
+            segments.push((start, i, None));
+            segments.push((i, i + repr.len(), Some(*id)));
+            i += repr.len();
+            start = i;
+        }
+        segments.push((start, data.len(), None));
+
+        Ok(segments)
+    }
+
+    /// Encodes every input as `encode` would.
+    pub fn encode_batch(&self, inputs: &[&[u8]]) -> Result<Vec<Vec<u32>>, String> {
+        inputs.iter().map(|data| self.encode(data)).collect()
+    }
+
+    /// `encode_batch` spread over `threads` threads. Inputs are cut at pre-tokenizer boundaries
+    /// into pieces of similar size, so one large input is shared between threads too, and the
+    /// output is identical to encoding each input sequentially.
+    pub fn encode_batch_parallel(
+        &self,
+        inputs: &[&[u8]],
+        threads: usize,
+    ) -> Result<Vec<Vec<u32>>, String> {
+        self.encode_batch_parallel_with_options(inputs, threads, &EncodeOptions::default())
+    }
+
+    /// `encode_batch_parallel` with the options of `encode_with_options`. Special token strings
+    /// are found in each whole input first, only the text between them is cut into pieces.
+    pub fn encode_batch_parallel_with_options(
+        &self,
+        inputs: &[&[u8]],
+        threads: usize,
+        options: &EncodeOptions,
+    ) -> Result<Vec<Vec<u32>>, String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        let threads = threads.max(1);
+        let total: usize = inputs.iter().map(|data| data.len()).sum();
+        let piece_len = total.div_ceil(threads).max(1);
+        let pre_tokenizer = self.config.pre_tokenizer.get();
+
+        // input index and either a piece of text or a special token
+        let mut pieces: Vec<(usize, &[u8], Option<u32>)> = Vec::new();
+        for (i, data) in inputs.iter().enumerate() {
+            for (start, end, special) in self.segments(data, options)? {
+                if special.is_some() {
+                    pieces.push((i, &[], special));
+                    continue;
+                }
+
+                let text = &data[start..end];
+                let mut start = 0;
+                while start < text.len() {
+                    let end = pre_tokenizer.boundary(text, start + piece_len);
+                    pieces.push((i, &text[start..end], None));
+                    start = end;
+                }
+            }
+        }
+
+        let per_thread = pieces.len().div_ceil(threads).max(1);
+        let encoded: Result<Vec<Vec<u32>>, String> = thread::scope(|scope| {
+            let handles: Vec<_> = pieces
+                .chunks(per_thread)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|(_, piece, special)| match special {
+                                Some(id) => Ok(vec![*id]),
+                                None => self.encode_text(piece),
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect()
+        });
+
+        let bos = self
+            .special_tokens
+            .get(&SpecialToken::Bos)
+            .filter(|_| options.add_bos);
+        let mut outputs: Vec<Vec<u32>> = vec![bos.into_iter().copied().collect(); inputs.len()];
+        for ((i, _, _), tokens) in pieces.iter().zip(encoded?) {
+            outputs[*i].extend(tokens);
+        }
+
+        if options.add_eos
+            && let Some(&eos_id) = self.special_tokens.get(&SpecialToken::Eos)
+        {
+            outputs.iter_mut().for_each(|tokens| tokens.push(eos_id));
+        }
+
+        Ok(outputs)
+    }
+
+    /// Encodes one large input on `threads` threads, identical to `encode`.
+    pub fn encode_parallel(&self, data: &[u8], threads: usize) -> Result<Vec<u32>, String> {
+        let mut outputs = self.encode_batch_parallel(&[data], threads)?;
+        Ok(outputs.remove(0))
+    }
+
+    /// Encodes data as plain text: special token strings are not recognized and no eos is added.
+    pub fn encode_ordinary(&self, data: &[u8]) -> Result<Vec<u32>, String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        self.encode_text(data)
+    }
+
+    // encodes data without adding any special tokens
+    fn encode_text(&self, data: &[u8]) -> Result<Vec<u32>, String> {
+        let mut tokens: Vec<u32> = Vec::new();
+        for (start, end) in self.config.pre_tokenizer.get().split(data) {
+            self.encode_word(&data[start..end], &mut tokens)?;
+        }
+        Ok(tokens)
+    }
+
+    /// The original encoder, which rescans each word for its best pair after every merge. Only
+    /// built with the `bench` feature, as the baseline of the encode benchmark.
+    #[cfg(feature = "bench")]
+    pub fn encode_unoptimized(&self, data: &[u8]) -> Result<Vec<u32>, String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        let mut tokens: Vec<u32> = Vec::new();
+
+        let mut words = self.words(data)?;
 
         // add eos
         if let Some(&eos_id) = self.special_tokens.get(&SpecialToken::Eos) {
@@ -319,8 +1079,304 @@ impl BpeTokenizer {
         Ok(tokens)
     }
 
+    // appends the tokens of one pre-tokenized word, going through the cache
+    fn encode_word(&self, word: &[u8], tokens: &mut Vec<u32>) -> Result<(), String> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(cached) = cache.get(word) {
+            tokens.extend_from_slice(cached);
+            return Ok(());
+        }
+        drop(cache); // merging does not need the lock
+
+        let merged: Box<[u32]> = self
+            .merge(&self.symbols(word)?)
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect();
+        tokens.extend_from_slice(&merged);
+
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.put(word.into(), merged);
+        Ok(())
+    }
+
+    /// Applies merges to a word in rank order. Symbols form a linked list and candidate pairs sit in
+    /// a min-heap keyed by (rank, position), so a word of n symbols takes O(n log n) instead of
+    /// rescanning it after every merge. Returns every final token with the index of the first
+    /// symbol it covers.
+    fn merge(&self, symbols: &[u32]) -> Vec<(u32, usize)> {
+        self.merge_with(symbols, || true)
+    }
+
+    // merges as `merge` does, asking `keep` before each merge. A skipped merge is retried after the
+    // next one that is applied, and merging stops once every candidate has been skipped.
+    fn merge_with<F>(&self, symbols: &[u32], mut keep: F) -> Vec<(u32, usize)>
+    where
+        F: FnMut() -> bool,
+    {
+        let n = symbols.len();
+        let mut tokens = symbols.to_vec();
+        let mut prev: Vec<usize> = (0..n).map(|i| i.wrapping_sub(1)).collect(); // usize::MAX if none
+        let mut next: Vec<usize> = (1..=n).collect(); // n if none
+        let mut alive = vec![true; n];
+
+        let mut heap = BinaryHeap::new();
+        let mut skipped = Vec::new();
+        for i in 1..n {
+            if let Some(&rank) = self.merge_rank.get(&(tokens[i - 1], tokens[i])) {
+                heap.push(Reverse((rank, i - 1)));
+            }
+        }
+
+        while let Some(Reverse((rank, i))) = heap.pop() {
+            let j = next[i];
+            if !alive[i] || j >= n {
+                continue;
+            }
+
+            // skip entries whose pair changed since they were pushed
+            let pair = (tokens[i], tokens[j]);
+            if self.merge_rank.get(&pair) != Some(&rank) {
+                continue;
+            }
+
+            if !keep() {
+                skipped.push(Reverse((rank, i)));
+                continue;
+            }
+            heap.extend(skipped.drain(..));
+
+            tokens[i] = self.pair_to_token[&pair];
+            alive[j] = false;
+            next[i] = next[j];
+            if next[i] < n {
+                prev[next[i]] = i;
+                if let Some(&rank) = self.merge_rank.get(&(tokens[i], tokens[next[i]])) {
+                    heap.push(Reverse((rank, i)));
+                }
+            }
+            if prev[i] < n
+                && let Some(&rank) = self.merge_rank.get(&(tokens[prev[i]], tokens[i]))
+            {
+                heap.push(Reverse((rank, prev[i])));
+            }
+        }
+
+        (0..n)
+            .filter(|i| alive[*i])
+            .map(|i| (tokens[i], i))
+            .collect()
+    }
+
+    // splits data with the configured pre-tokenizer into per-word symbols
+    #[cfg(feature = "bench")]
+    fn words(&self, data: &[u8]) -> Result<Vec<Vec<u32>>, String> {
+        self.config
+            .pre_tokenizer
+            .get()
+            .split(data)
+            .into_iter()
+            .map(|(start, end)| self.symbols(&data[start..end]))
+            .collect()
+    }
+
+    // byte ids of a single word, closed with eow if configured. Only the unk policy allows bytes
+    // without a token, they become unk.
+    fn symbols(&self, word: &[u8]) -> Result<Vec<u32>, String> {
+        let unk_id = self.special_tokens.get(&SpecialToken::Unk).copied();
+        let mut symbols = word
+            .iter()
+            .map(|c| {
+                self.byte_ids[*c as usize]
+                    .or(unk_id)
+                    .ok_or_else(|| format!("Vocabulary has no token for byte {:#04x}", c))
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+        if let Some(&eow_id) = self.special_tokens.get(&SpecialToken::Eow) {
+            symbols.push(eow_id);
+        }
+        Ok(symbols)
+    }
+
     pub fn build(&mut self, data: &[u8]) {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        self.build_with_threads(data, threads);
+    }
+
+    /// Same as `build`, pre-tokenizing and counting on `threads` threads. The merges do not depend
+    /// on the thread count.
+    pub fn build_with_threads(&mut self, data: &[u8], threads: usize) {
+        // deduplicate words so each distinct word is only merged once
+        let specials = self.special_reprs();
+        let word_counts =
+            train::count_words(self.config.pre_tokenizer.get(), &specials, data, threads);
+        self.train(word_counts.into_iter().collect(), threads);
+    }
+
+    /// Trains on a corpus streamed as byte chunks, so it never has to be in memory at once. Chunks
+    /// may cut through words, they are joined back before pre-tokenizing.
+    pub fn build_from_chunks<I, C>(&mut self, chunks: I)
+    where
+        I: IntoIterator<Item = C>,
+        C: AsRef<[u8]>,
+    {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut counter = WordCounter::new(
+            self.config.pre_tokenizer.get(),
+            self.special_reprs(),
+            threads,
+        );
+        for chunk in chunks {
+            counter.feed(chunk.as_ref());
+        }
+        counter.flush();
+
+        let word_counts = counter.into_counts();
+        self.train(
+            word_counts
+                .iter()
+                .map(|(word, count)| (word.as_ref(), *count))
+                .collect(),
+            threads,
+        );
+    }
+
+    /// Trains on the concatenated contents of `paths`, read in blocks. Words never span two files.
+    pub fn build_from_files(&mut self, paths: &[&str]) -> Result<(), String> {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut counter = WordCounter::new(
+            self.config.pre_tokenizer.get(),
+            self.special_reprs(),
+            threads,
+        );
+        let mut block = vec![0_u8; STREAM_BLOCK_SIZE];
+        for path in paths.iter() {
+            let mut file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+            loop {
+                let read = file
+                    .read(&mut block)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                if read == 0 {
+                    break;
+                }
+                counter.feed(&block[..read]);
+            }
+            counter.flush();
+        }
+
+        let word_counts = counter.into_counts();
+        self.train(
+            word_counts
+                .iter()
+                .map(|(word, count)| (word.as_ref(), *count))
+                .collect(),
+            threads,
+        );
+        Ok(())
+    }
+
+    /// Continues training on new data until the vocabulary holds `vocab_size` tokens. Words start
+    /// out as the current tokenizer encodes them and new merges rank after every existing one, so
+    /// existing ids and the encoding of text without the new merges stay the same.
+    pub fn extend(&mut self, data: &[u8], vocab_size: usize) -> Result<(), String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        if vocab_size < self.i2t.len() {
+            return Err(format!(
+                "Cannot shrink the vocabulary from {} to {} tokens",
+                self.i2t.len(),
+                vocab_size
+            ));
+        }
+
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let specials = self.special_reprs();
+        let mut words: Vec<(&[u8], u64)> =
+            train::count_words(self.config.pre_tokenizer.get(), &specials, data, threads)
+                .into_iter()
+                .collect();
+        words.sort_unstable();
+
+        let mut queue = MergeQueue::new(
+            words
+                .into_iter()
+                .map(|(word, count)| {
+                    let tokens = self.merge(&self.symbols(word)?);
+                    Ok((tokens.into_iter().map(|(token, _)| token).collect(), count))
+                })
+                .collect::<Result<_, String>>()?,
+            threads,
+        );
+
+        self.config.vocab_size = vocab_size;
+        self.learn_merges(&mut queue);
+        self.cache
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        Ok(())
+    }
+
+    /// Registers a special token on a built tokenizer and returns its id, the next free one. The
+    /// end of word token cannot be added since every word would encode differently.
+    pub fn add_special_token(&mut self, token: SpecialToken) -> Result<u32, String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        if token == SpecialToken::Eow {
+            return Err("The end of word token cannot be added to a built tokenizer".to_string());
+        }
+
+        if token.repr().is_empty() {
+            return Err("Special token names must not be empty".to_string());
+        }
+
+        if self
+            .config
+            .special_tokens
+            .iter()
+            .any(|other| other.repr() == token.repr())
+        {
+            return Err(format!(
+                "Special token {} is registered twice",
+                String::from_utf8_lossy(&token.repr())
+            ));
+        }
+
+        let id = self.i2t.len() as u32;
+        self.i2t.push(token.repr().into_boxed_slice());
+        self.special_tokens.insert(token.clone(), id);
+        self.config.special_tokens.push(token);
+        self.config.vocab_size = self.config.vocab_size.max(self.i2t.len());
+        self.cache
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        Ok(id)
+    }
+
+    // names of the configured special tokens, longest first so the longest match wins
+    fn special_reprs(&self) -> Vec<Vec<u8>> {
+        let mut reprs: Vec<Vec<u8>> = self
+            .config
+            .special_tokens
+            .iter()
+            .map(|token| token.repr())
+            .collect();
+        reprs.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+        reprs
+    }
+
+    // resets the vocabulary and learns merges from distinct words and their frequencies
+    fn train(&mut self, mut words: Vec<(&[u8], u64)>, threads: usize) {
         // reset prior vocab data
+        self.cache
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
         self.i2t.clear();
         self.t2i.clear();
         self.merge_rank.clear();
@@ -328,77 +1384,47 @@ impl BpeTokenizer {
         self.special_tokens.clear();
 
         // first load all 256 bytes
+        self.byte_ids = (0..256).map(Some).collect();
         (0..256).for_each(|i| {
             self.i2t.push(Box::new([i as u8]));
             self.t2i.insert(Box::new([i as u8]), i as u32);
         });
 
-        // load special tokens
+        // load special tokens. They stay out of t2i so a merge that happens to spell out a special
+        // token's name gets a regular token of its own.
         for special_token in self.config.special_tokens.iter() {
             let id: u32 = self.i2t.len() as u32;
-            self.special_tokens.insert(*special_token, id);
-
-            let repr = special_token.repr();
-            self.i2t.push(repr.clone().into_boxed_slice()); // leave special tokens to have empty bytes for now
-            self.t2i.insert(repr.into_boxed_slice(), id);
+            self.special_tokens.insert(special_token.clone(), id);
+            self.i2t.push(special_token.repr().into_boxed_slice());
         }
 
-        // divide data input into 2d token id array
-        let mut words: Vec<Vec<u32>> = Vec::new();
-        let mut cur: Vec<u32> = Vec::new();
-        for c in data.iter() {
-            if c.is_ascii_alphanumeric() {
-                // TODO: add proper unicode support via a regex pretokenizer
-                cur.push(*c as u32);
-            } else {
-                if cur.len() > 0 {
-                    if let Some(&eow_id) = self.special_tokens.get(&SpecialToken::Eow) {
-                        cur.push(eow_id);
-                    }
-                    words.push(cur);
-                    cur = Vec::new();
-                }
-
-                // then add the non alpha character as its own word
-                cur.push(*c as u32);
-                if let Some(&eow_id) = self.special_tokens.get(&SpecialToken::Eow) {
-                    cur.push(eow_id);
-                }
-                words.push(cur);
-                cur = Vec::new();
-            }
-        }
+        // every byte is its own id after the reset
+        let eow_id = self.special_tokens.get(&SpecialToken::Eow).copied();
+        words.sort_unstable();
+        let mut queue = MergeQueue::new(
+            words
+                .into_iter()
+                .map(|(word, count)| {
+                    (
+                        word.iter().map(|b| *b as u32).chain(eow_id).collect(),
+                        count,
+                    )
+                })
+                .collect(),
+            threads,
+        );
 
-        // clean up and add last word
-        if cur.len() > 0 {
-            if let Some(&eow_id) = self.special_tokens.get(&SpecialToken::Eow) {
-                cur.push(eow_id);
-            }
-            words.push(cur);
-        }
-
-        // add eos
-        if let Some(&eos_id) = self.special_tokens.get(&SpecialToken::Eos) {
-            words.push(vec![eos_id]);
-        }
+        self.learn_merges(&mut queue);
+        self.built = true;
+    }
 
-        // now perform recursive merging
+    // performs the most frequent merges until the vocabulary is full, ranked after existing merges
+    fn learn_merges(&mut self, queue: &mut MergeQueue) {
         while self.i2t.len() < self.config.vocab_size {
-            let pair_counts = words.iter().flat_map(|word| word.windows(2)).fold(
-                HashMap::new(),
-                |mut counts, window| {
-                    *counts.entry((window[0], window[1])).or_insert(0) += 1;
-                    counts
-                },
-            );
-
-            let (&best_pair, _) = match pair_counts.iter().max_by_key(|(_, count)| *count) {
-                Some(pair) => pair,
-                None => {
-                    self.built = true;
-                    return;
-                }
+            let Some(best_pair) = queue.pop() else {
+                break;
             };
+
             let mut new_token_id = self.i2t.len() as u32;
             let merged_bytes = [
                 self.i2t[best_pair.0 as usize].as_ref(),
@@ -414,22 +1440,706 @@ impl BpeTokenizer {
                     .insert(merged_bytes.into_boxed_slice(), new_token_id);
             }
 
-            self.merge_rank.insert(best_pair, self.merge_rank.len());
-            self.pair_to_token.insert(best_pair, new_token_id);
+            // a pair can come back through a reused token, it keeps its first rank
+            if !self.merge_rank.contains_key(&best_pair) {
+                self.merge_rank.insert(best_pair, self.merge_rank.len());
+                self.pair_to_token.insert(best_pair, new_token_id);
+            }
+            queue.merge(best_pair, new_token_id);
+        }
+    }
+}
+
+impl Tokenizer for BpeTokenizer {
+    fn encode(&self, data: &[u8]) -> Result<Vec<u32>, String> {
+        BpeTokenizer::encode(self, data)
+    }
+
+    fn encode_ordinary(&self, data: &[u8]) -> Result<Vec<u32>, String> {
+        BpeTokenizer::encode_ordinary(self, data)
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<Vec<u8>, String> {
+        BpeTokenizer::decode(self, tokens)
+    }
+
+    fn vocab_size(&self) -> usize {
+        BpeTokenizer::vocab_size(self)
+    }
+
+    fn special_token_id(&self, token: &SpecialToken) -> Option<u32> {
+        BpeTokenizer::special_token_id(self, token)
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        self.save_to_binary(path)
+    }
+
+    fn load(path: &str) -> Result<Self, String> {
+        Self::load_from_binary(path)
+    }
+}
+
+pub(super) fn write_special_tags(
+    writer: &mut dyn Write,
+    tokens: &[SpecialToken],
+) -> Result<(), String> {
+    utils::write_u32(writer, tokens.len() as u32)?;
+    for token in tokens.iter() {
+        token.write(writer)?; // saves the special tokens as a numeric tag
+    }
+    Ok(())
+}
+
+pub(super) fn read_special_tags(reader: &mut dyn Read) -> Result<Vec<SpecialToken>, String> {
+    let count = utils::read_u32(reader)?;
+    let mut tokens = Vec::new();
+    for _ in 0..count {
+        tokens.push(SpecialToken::read(reader)?);
+    }
+    Ok(tokens)
+}
+
+fn read_vocab(reader: &mut dyn Read) -> Result<Vec<Box<[u8]>>, String> {
+    let vocab_len = utils::read_u32(reader)?;
+    let mut i2t = Vec::new();
+    for _ in 0..vocab_len {
+        let token_bytes_len = utils::read_u32(reader)? as usize;
+        let token_bytes = utils::read_bytes(reader, token_bytes_len)?;
+        i2t.push(token_bytes.into_boxed_slice());
+    }
+    Ok(i2t)
+}
+
+pub(super) fn write_special_ids(
+    writer: &mut dyn Write,
+    special_tokens: &HashMap<SpecialToken, u32>,
+) -> Result<(), String> {
+    let mut specials: Vec<(&SpecialToken, u32)> =
+        special_tokens.iter().map(|(k, v)| (k, *v)).collect();
+    specials.sort_by_key(|(_, id)| *id);
+    utils::write_u32(writer, specials.len() as u32)?;
+    for (token, id) in specials.iter() {
+        token.write(writer)?;
+        utils::write_u32(writer, *id)?;
+    }
+    Ok(())
+}
+
+pub(super) fn read_special_ids(
+    reader: &mut dyn Read,
+) -> Result<HashMap<SpecialToken, u32>, String> {
+    let mut special_tokens = HashMap::new();
+    let count = utils::read_u32(reader)?;
+    for _ in 0..count {
+        let token = SpecialToken::read(reader)?;
+        let id = utils::read_u32(reader)?;
+        special_tokens.insert(token, id);
+    }
+    Ok(special_tokens)
+}
+
+fn write_pair_table(writer: &mut dyn Write, table: &[((u32, u32), u32)]) -> Result<(), String> {
+    utils::write_u32(writer, table.len() as u32)?;
+    for ((a_id, b_id), value) in table.iter() {
+        utils::write_u32(writer, *a_id)?;
+        utils::write_u32(writer, *b_id)?;
+        utils::write_u32(writer, *value)?;
+    }
+    Ok(())
+}
+
+fn read_pair_table(reader: &mut dyn Read) -> Result<PairTable, String> {
+    let count = utils::read_u32(reader)?;
+    let mut table = Vec::new();
+    for _ in 0..count {
+        let a = utils::read_u32(reader)?;
+        let b = utils::read_u32(reader)?;
+        let value = utils::read_u32(reader)?;
+        table.push(((a, b), value));
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::pretokenizer::PreTokenizer;
+    use crate::token::utils::TempFile;
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::sync::Arc;
+
+    const CORPUS: &str = "The quick brown fox jumps over the lazy dog. It's 2024, naïve café \
+        déjà vu! 東京は晴れ。 fn main() { println!(\"hi\"); } <|sep|> done\n\n  ok";
+
+    fn trained(config: BpeConfig) -> BpeTokenizer {
+        let mut tokenizer = BpeTokenizer::new(config).unwrap();
+        tokenizer.build_with_threads(CORPUS.repeat(20).as_bytes(), 2);
+        tokenizer
+    }
+
+    #[test]
+    fn merges_do_not_depend_on_the_thread_count() {
+        let corpus = "<|tool call|> lookup weather <|tool call|> result: sunny and warm. \
+            Tomorrow looks cloudy, tool calls call tools. "
+            .repeat(40);
+        let build = |threads: usize| {
+            let mut tokenizer = BpeTokenizer::new(BpeConfig {
+                vocab_size: 360,
+                special_tokens: vec![SpecialToken::Eos, SpecialToken::named("<|tool call|>")],
+                ..BpeConfig::default()
+            })
+            .unwrap();
+            tokenizer.build_with_threads(corpus.as_bytes(), threads);
+            tokenizer
+        };
+
+        let single = build(1);
+        assert!(!single.t2i.contains_key(&b"<|"[..]));
+        for threads in [2, 3, 5, 16] {
+            let parallel = build(threads);
+            assert_eq!(parallel.i2t, single.i2t, "{} threads", threads);
+            assert_eq!(
+                parallel.merge_rank, single.merge_rank,
+                "{} threads",
+                threads
+            );
+        }
+    }
+
+    #[test]
+    fn chunked_builds_match_one_shot_builds() {
+        let corpus = format!(
+            "{}{}{}",
+            "東京は今日も晴れ。明日は雨でしょう、傘を忘れずに！".repeat(300),
+            "let x=f(a,b);if(x>1){y=g(x);}else{y=h(x);}<|eos|>".repeat(300),
+            CORPUS.repeat(30)
+        );
+        for pre_tokenizer in [
+            PreTokenizerKind::Ascii,
+            PreTokenizerKind::Gpt2,
+            PreTokenizerKind::Cl100k,
+        ] {
+            let config = || BpeConfig {
+                vocab_size: 500,
+                pre_tokenizer: pre_tokenizer.clone(),
+                ..BpeConfig::default()
+            };
+            let mut one_shot = BpeTokenizer::new(config()).unwrap();
+            one_shot.build(corpus.as_bytes());
+
+            for chunk_len in [5, 64, 4096] {
+                let mut chunked = BpeTokenizer::new(config()).unwrap();
+                chunked.build_from_chunks(corpus.as_bytes().chunks(chunk_len));
+                assert_eq!(
+                    chunked.i2t, one_shot.i2t,
+                    "{:?} {}",
+                    pre_tokenizer, chunk_len
+                );
+                assert_eq!(chunked.merge_rank, one_shot.merge_rank);
+            }
+        }
+    }
+
+    #[test]
+    fn special_strings_are_text_unless_disallowed() {
+        let tokenizer = trained(BpeConfig {
+            vocab_size: 300,
+            ..BpeConfig::default()
+        });
+        let eos_id = tokenizer.special_token_id(&SpecialToken::Eos).unwrap();
+        let text = b"the fox <|eos|> jumps";
+
+        let tokens = tokenizer.encode(text).unwrap();
+        assert_eq!(
+            tokens.iter().position(|id| *id == eos_id),
+            Some(tokens.len() - 1)
+        );
+        assert_eq!(tokenizer.decode(&tokens).unwrap(), text);
+
+        let options = EncodeOptions {
+            disallowed_special: SpecialSet::All,
+            ..EncodeOptions::default()
+        };
+        assert!(tokenizer.encode_with_options(text, &options).is_err());
+    }
+
+    #[test]
+    fn parallel_encoding_matches_sequential_encoding() {
+        let tokenizer = trained(BpeConfig {
+            vocab_size: 320,
+            special_tokens: vec![
+                SpecialToken::Eos,
+                SpecialToken::Bos,
+                SpecialToken::named("<|sep|>"),
+            ],
+            ..BpeConfig::default()
+        });
+        let long = CORPUS.repeat(7);
+        let inputs: Vec<&[u8]> = vec![
+            b"the fox <|eos|> jumps",
+            b"",
+            b"<|sep|><|sep|>",
+            CORPUS.as_bytes(),
+            long.as_bytes(),
+        ];
+
+        let options = [
+            EncodeOptions::default(),
+            EncodeOptions {
+                add_bos: true,
+                allowed_special: SpecialSet::All,
+                ..EncodeOptions::default()
+            },
+            EncodeOptions {
+                add_eos: false,
+                allowed_special: SpecialSet::Only(vec![SpecialToken::named("<|sep|>")]),
+                ..EncodeOptions::default()
+            },
+        ];
+        for threads in [1, 2, 3, 8] {
+            assert_eq!(
+                tokenizer.encode_batch_parallel(&inputs, threads).unwrap(),
+                tokenizer.encode_batch(&inputs).unwrap()
+            );
+            assert_eq!(
+                tokenizer.encode_parallel(long.as_bytes(), threads).unwrap(),
+                tokenizer.encode(long.as_bytes()).unwrap()
+            );
+
+            for options in options.iter() {
+                let sequential: Vec<Vec<u32>> = inputs
+                    .iter()
+                    .map(|data| tokenizer.encode_with_options(data, options).unwrap())
+                    .collect();
+                let parallel =
+                    tokenizer.encode_batch_parallel_with_options(&inputs, threads, options);
+                assert_eq!(parallel.unwrap(), sequential, "{} threads", threads);
+            }
+
+            let strict = EncodeOptions {
+                disallowed_special: SpecialSet::All,
+                ..EncodeOptions::default()
+            };
+            assert!(
+                tokenizer
+                    .encode_batch_parallel_with_options(&inputs, threads, &strict)
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn extending_keeps_existing_ids_and_encodings() {
+        let mut tokenizer = trained(BpeConfig {
+            vocab_size: 300,
+            special_tokens: vec![SpecialToken::Eos, SpecialToken::named("<|sep|>")],
+            ..BpeConfig::default()
+        });
+        let tokens: Vec<Box<[u8]>> = tokenizer.i2t.clone();
+        let specials = tokenizer.special_tokens.clone();
+        let encoded = tokenizer.encode(CORPUS.as_bytes()).unwrap();
+
+        // pairs that never occur in the corpus, so its encoding has nothing to merge
+        let domain = " ZZZZ QQQQ ZQZQ QZQZ".repeat(50);
+        tokenizer.extend(domain.as_bytes(), 310).unwrap();
+        assert_eq!(tokenizer.vocab_size(), 310);
+        assert_eq!(tokenizer.i2t[..tokens.len()], tokens[..]);
+        assert_eq!(tokenizer.special_tokens, specials);
+        assert_eq!(tokenizer.encode(CORPUS.as_bytes()).unwrap(), encoded);
+        assert!(
+            tokenizer
+                .encode(b" ZZZZ")
+                .unwrap()
+                .iter()
+                .any(|id| *id as usize >= tokens.len())
+        );
+
+        let tool = SpecialToken::named("<|tool|>");
+        let id = tokenizer.add_special_token(tool.clone()).unwrap();
+        assert_eq!(id, 310);
+        assert_eq!(tokenizer.i2t[..tokens.len()], tokens[..]);
+        assert_eq!(tokenizer.encode(CORPUS.as_bytes()).unwrap(), encoded);
+        let options = EncodeOptions {
+            add_eos: false,
+            allowed_special: SpecialSet::All,
+            ..EncodeOptions::default()
+        };
+        assert_eq!(
+            tokenizer
+                .encode_with_options(b"<|tool|>", &options)
+                .unwrap(),
+            [id]
+        );
+    }
+
+    // every token with a span holds exactly the bytes it covers, apart from a closing eow
+    fn assert_spans_cover(tokenizer: &BpeTokenizer, data: &[u8], offsets: &TokenOffsets) {
+        let eow = SpecialToken::Eow.repr();
+        let mut end = 0;
+        for (id, span) in offsets.iter() {
+            let bytes = tokenizer.token_bytes(*id).unwrap();
+            match span {
+                Some((start, stop)) => {
+                    assert_eq!(*start, end);
+                    assert_eq!(
+                        bytes.strip_suffix(eow.as_slice()).unwrap_or(bytes),
+                        &data[*start..*stop]
+                    );
+                    end = *stop;
+                }
+                None => assert!(bytes == eow || tokenizer.special_tokens.values().any(|s| s == id)),
+            }
+        }
+        assert_eq!(end, data.len());
+    }
+
+    #[test]
+    fn offsets_cover_the_input() {
+        let text = "naïve café, 東京は晴れ! the fox <|sep|> ok".as_bytes();
+        let options = EncodeOptions::default();
+
+        let bytes_only = trained(BpeConfig {
+            vocab_size: 257,
+            ..BpeConfig::default()
+        });
+        let offsets = bytes_only
+            .encode_with_offsets("aé東".as_bytes(), &options)
+            .unwrap();
+        let eos_id = bytes_only.special_token_id(&SpecialToken::Eos).unwrap();
+        let spans: Vec<_> = offsets.iter().map(|(_, span)| *span).collect();
+        assert_eq!(
+            spans,
+            [
+                Some((0, 1)),
+                Some((1, 2)),
+                Some((2, 3)),
+                Some((3, 4)),
+                Some((4, 5)),
+                Some((5, 6)),
+                None
+            ]
+        );
+        assert_eq!(offsets.last().unwrap().0, eos_id);
+
+        for special_tokens in [
+            vec![SpecialToken::Eos, SpecialToken::named("<|sep|>")],
+            vec![
+                SpecialToken::Eos,
+                SpecialToken::Eow,
+                SpecialToken::named("<|sep|>"),
+            ],
+        ] {
+            let tokenizer = trained(BpeConfig {
+                vocab_size: 330,
+                special_tokens,
+                ..BpeConfig::default()
+            });
+            let offsets = tokenizer.encode_with_offsets(text, &options).unwrap();
+            let ids: Vec<u32> = offsets.iter().map(|(id, _)| *id).collect();
+            assert_eq!(ids, tokenizer.encode(text).unwrap());
+            assert_spans_cover(&tokenizer, text, &offsets);
+        }
+
+        // eow closes every word as a token of its own when nothing merges it
+        let eow_bytes = trained(BpeConfig {
+            vocab_size: 258,
+            special_tokens: vec![SpecialToken::Eos, SpecialToken::Eow],
+            ..BpeConfig::default()
+        });
+        let offsets = eow_bytes.encode_with_offsets(b"ab c", &options).unwrap();
+        let spans: Vec<_> = offsets.iter().map(|(_, span)| *span).collect();
+        assert_eq!(
+            spans,
+            [
+                Some((0, 1)),
+                Some((1, 2)),
+                None,
+                Some((2, 3)),
+                Some((3, 4)),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn offsets_of_special_tokens() {
+        let tokenizer = trained(BpeConfig {
+            vocab_size: 300,
+            special_tokens: vec![
+                SpecialToken::Eos,
+                SpecialToken::Bos,
+                SpecialToken::named("<|sep|>"),
+            ],
+            ..BpeConfig::default()
+        });
+        let sep_id = tokenizer
+            .special_token_id(&SpecialToken::named("<|sep|>"))
+            .unwrap();
+        let bos_id = tokenizer.special_token_id(&SpecialToken::Bos).unwrap();
+        let text = b"ok<|sep|>";
+
+        // as text, the special string is covered by ordinary tokens
+        let offsets = tokenizer
+            .encode_with_offsets(text, &EncodeOptions::default())
+            .unwrap();
+        assert!(offsets.iter().all(|(id, _)| *id != sep_id));
+        assert_spans_cover(&tokenizer, text, &offsets);
+
+        // an allowed special token covers its literal string, bos and eos cover nothing
+        let options = EncodeOptions {
+            add_bos: true,
+            add_eos: false,
+            allowed_special: SpecialSet::All,
+            ..EncodeOptions::default()
+        };
+        let offsets = tokenizer.encode_with_offsets(text, &options).unwrap();
+        assert_eq!(offsets.first(), Some(&(bos_id, None)));
+        assert_eq!(offsets.last(), Some(&(sep_id, Some((2, 9)))));
+        assert_spans_cover(&tokenizer, text, &offsets);
+    }
+
+    #[test]
+    fn dropout_between_the_encoding_and_single_bytes() {
+        let tokenizer = trained(BpeConfig {
+            vocab_size: 330,
+            ..BpeConfig::default()
+        });
+        let eos_id = tokenizer.special_token_id(&SpecialToken::Eos).unwrap();
+        let text = CORPUS.as_bytes();
+        let mut rng = StdRng::seed_from_u64(7);
+
+        let encoded = tokenizer.encode(text).unwrap();
+        assert_eq!(
+            tokenizer.encode_with_dropout(text, 0.0, &mut rng).unwrap(),
+            encoded
+        );
+
+        let bytes = tokenizer.encode_with_dropout(text, 1.0, &mut rng).unwrap();
+        assert_eq!(bytes.len(), text.len() + 1);
+        assert!(
+            bytes[..text.len()]
+                .iter()
+                .all(|id| tokenizer.token_bytes(*id).unwrap().len() == 1)
+        );
+        assert_eq!(bytes.last(), Some(&eos_id));
+
+        let sample = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            tokenizer.encode_with_dropout(text, 0.3, &mut rng).unwrap()
+        };
+        assert_eq!(sample(1), sample(1));
+        assert_ne!(sample(1), sample(2));
+        assert_eq!(tokenizer.decode(&sample(3)).unwrap(), text);
+    }
+
+    #[test]
+    fn save_and_load_keep_encode_and_decode() {
+        let configs = [
+            BpeConfig {
+                vocab_size: 400,
+                ..BpeConfig::default()
+            },
+            BpeConfig {
+                vocab_size: 350,
+                special_tokens: vec![
+                    SpecialToken::Eos,
+                    SpecialToken::Eow,
+                    SpecialToken::named("<|sep|>"),
+                ],
+                pre_tokenizer: PreTokenizerKind::Ascii,
+                unknown_policy: UnknownPolicy::ByteFallback,
+            },
+            BpeConfig {
+                vocab_size: 380,
+                special_tokens: vec![SpecialToken::Unk, SpecialToken::Bos],
+                pre_tokenizer: PreTokenizerKind::Cl100k,
+                unknown_policy: UnknownPolicy::Unk,
+            },
+        ];
+
+        let options = EncodeOptions {
+            add_bos: true,
+            allowed_special: SpecialSet::All,
+            ..EncodeOptions::default()
+        };
+        for (i, config) in configs.into_iter().enumerate() {
+            let tokenizer = trained(config);
+            let path = TempFile::new(&format!("round-trip-{}.bin", i));
+            tokenizer.save_to_binary(&path).unwrap();
+            let loaded = BpeTokenizer::load_from_binary(&path).unwrap();
 
-            for word in words.iter_mut() {
-                let mut j = 1;
-                while j < word.len() {
-                    if word[j - 1] == best_pair.0 && word[j] == best_pair.1 {
-                        word[j - 1] = new_token_id;
-                        word.remove(j);
-                    } else {
-                        j += 1;
+            let ids = tokenizer
+                .encode_with_options(CORPUS.as_bytes(), &options)
+                .unwrap();
+            assert_eq!(
+                loaded
+                    .encode_with_options(CORPUS.as_bytes(), &options)
+                    .unwrap(),
+                ids
+            );
+            assert_eq!(
+                loaded.decode(&ids).unwrap(),
+                tokenizer.decode(&ids).unwrap()
+            );
+            assert_eq!(loaded.special_tokens(), tokenizer.special_tokens());
+            assert_eq!(
+                loaded.config().pre_tokenizer,
+                tokenizer.config().pre_tokenizer
+            );
+            assert_eq!(
+                loaded.config().unknown_policy,
+                tokenizer.config().unknown_policy
+            );
+            assert_eq!(loaded.vocab_size(), tokenizer.vocab_size());
+        }
+    }
+
+    // words are runs of non-whitespace bytes, every whitespace byte is a word of its own
+    struct WhitespacePreTokenizer;
+
+    impl PreTokenizer for WhitespacePreTokenizer {
+        fn split(&self, data: &[u8]) -> Vec<(usize, usize)> {
+            let mut words = Vec::new();
+            let mut start = 0;
+            for (i, b) in data.iter().enumerate() {
+                if b.is_ascii_whitespace() {
+                    if start < i {
+                        words.push((start, i));
                     }
+                    words.push((i, i + 1));
+                    start = i + 1;
                 }
             }
+            if start < data.len() {
+                words.push((start, data.len()));
+            }
+            words
         }
 
-        self.built = true;
+        fn boundary(&self, data: &[u8], from: usize) -> usize {
+            (from.max(1)..data.len())
+                .find(|i| data[*i].is_ascii_whitespace())
+                .unwrap_or(data.len())
+        }
+    }
+
+    #[test]
+    fn custom_pre_tokenizers_train_and_encode_but_are_not_saved() {
+        let pre_tokenizer = PreTokenizerKind::Custom(Arc::new(WhitespacePreTokenizer));
+        assert_eq!(pre_tokenizer.clone(), pre_tokenizer);
+        assert_ne!(
+            PreTokenizerKind::Custom(Arc::new(WhitespacePreTokenizer)),
+            pre_tokenizer
+        );
+
+        let tokenizer = trained(BpeConfig {
+            vocab_size: 400,
+            pre_tokenizer,
+            ..BpeConfig::default()
+        });
+        let ids = tokenizer.encode(CORPUS.as_bytes()).unwrap();
+        assert_eq!(tokenizer.decode(&ids).unwrap(), CORPUS.as_bytes());
+        assert_eq!(
+            tokenizer
+                .encode_batch_parallel(&[CORPUS.as_bytes()], 3)
+                .unwrap(),
+            [ids.clone()]
+        );
+        assert!(tokenizer.i2t.iter().any(|token| token.ends_with(b".")
+            && token.len() > 1
+            && token[0].is_ascii_alphabetic()));
+        for id in ids {
+            let token = tokenizer.decode(&[id]).unwrap();
+            assert!(token.len() == 1 || !token.iter().any(u8::is_ascii_whitespace));
+        }
+
+        let path = TempFile::new("custom.bin");
+        assert!(tokenizer.save_to_binary(&path).is_err());
+        assert!(tokenizer.save_to_hf_json(&path).is_err());
+        assert!(fs::metadata(&path).is_err());
+    }
+
+    // saved by the unversioned writer, with the ids it encoded `LEGACY_TEXT` to
+    const LEGACY_FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/legacy_tokenizer.bin"
+    );
+    const LEGACY_TEXT: &[u8] = b"the cat met the dog on a log.";
+    const LEGACY_IDS: &[u32] = &[
+        259, 32, 263, 32, 268, 32, 259, 32, 265, 32, 261, 32, 97, 32, 266, 46, 256,
+    ];
+
+    #[test]
+    fn legacy_files_load_and_upgrade() {
+        let tokenizer = BpeTokenizer::load_from_binary(LEGACY_FIXTURE).unwrap();
+        assert_eq!(tokenizer.config().pre_tokenizer, PreTokenizerKind::Ascii);
+        assert_eq!(tokenizer.encode(LEGACY_TEXT).unwrap(), LEGACY_IDS);
+        assert_eq!(tokenizer.decode(LEGACY_IDS).unwrap(), LEGACY_TEXT);
+
+        let path = TempFile::new("legacy-upgraded.bin");
+        tokenizer.save_to_binary(&path).unwrap();
+        let upgraded = BpeTokenizer::load_from_binary(&path).unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(MAGIC));
+        assert_eq!(upgraded.encode(LEGACY_TEXT).unwrap(), LEGACY_IDS);
+    }
+
+    #[test]
+    fn damaged_files_are_errors() {
+        let text = TempFile::new("not-a-tokenizer.txt");
+        fs::write(
+            &text,
+            "this is a plain text file and not a tokenizer\n".repeat(50),
+        )
+        .unwrap();
+        assert!(BpeTokenizer::load_from_binary(&text).is_err());
+
+        let legacy = fs::read(LEGACY_FIXTURE).unwrap();
+        let truncated = TempFile::new("legacy-truncated.bin");
+        fs::write(&truncated, &legacy[..legacy.len() - 7]).unwrap();
+        assert!(BpeTokenizer::load_from_binary(&truncated).is_err());
+
+        let saved = TempFile::new("saved.bin");
+        trained(BpeConfig {
+            vocab_size: 300,
+            ..BpeConfig::default()
+        })
+        .save_to_binary(&saved)
+        .unwrap();
+        let bytes = fs::read(&saved).unwrap();
+
+        let cut = TempFile::new("saved-truncated.bin");
+        fs::write(&cut, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(BpeTokenizer::load_from_binary(&cut).is_err());
+
+        let mut flipped = bytes.clone();
+        flipped[40] ^= 0xFF;
+        let corrupt = TempFile::new("saved-corrupt.bin");
+        fs::write(&corrupt, &flipped).unwrap();
+        assert!(BpeTokenizer::load_from_binary(&corrupt).is_err());
+    }
+
+    #[test]
+    fn unknown_policies_without_their_tokens_are_errors() {
+        let path = TempFile::new("policy.bin");
+        let config = || BpeConfig {
+            vocab_size: 300,
+            ..BpeConfig::default()
+        };
+
+        let mut no_unk = trained(config());
+        no_unk.config.unknown_policy = UnknownPolicy::Unk;
+        no_unk.save_to_binary(&path).unwrap();
+        assert!(BpeTokenizer::load_from_binary(&path).is_err());
+
+        for policy in [UnknownPolicy::ByteFallback, UnknownPolicy::Strict] {
+            let mut missing_byte = trained(config());
+            missing_byte.config.unknown_policy = policy;
+            missing_byte.byte_ids[b'a' as usize] = None;
+            assert!(missing_byte.encode(b"a cat").is_err());
+            missing_byte.save_to_binary(&path).unwrap();
+            assert!(BpeTokenizer::load_from_binary(&path).is_err());
+        }
     }
 }
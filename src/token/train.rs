@@ -0,0 +1,350 @@
+use super::pretokenizer::PreTokenizer;
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    thread,
+};
+
+/// Counts the distinct words of `data`, skipping any literal occurrence of `specials` so that
+/// special token strings are never learned as text. The input is cut at pre-tokenizer boundaries
+/// outside of special token strings into one chunk per thread, so the counts are the same for any
+/// thread count.
+pub(super) fn count_words<'a>(
+    pre_tokenizer: &dyn PreTokenizer,
+    specials: &[Vec<u8>],
+    data: &'a [u8],
+    threads: usize,
+) -> HashMap<&'a [u8], u64> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let target = start + (data.len() / threads.max(1)).max(1);
+        let end = boundary(pre_tokenizer, specials, data, target);
+        chunks.push(&data[start..end]);
+        start = end;
+    }
+
+    let partials: Vec<HashMap<&[u8], u64>> = thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let mut counts: HashMap<&[u8], u64> = HashMap::new();
+                    for text in without_specials(chunk, specials) {
+                        for (start, end) in pre_tokenizer.split(text) {
+                            *counts.entry(&text[start..end]).or_insert(0) += 1;
+                        }
+                    }
+                    counts
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let mut counts = HashMap::new();
+    for partial in partials {
+        for (word, count) in partial {
+            *counts.entry(word).or_insert(0) += count;
+        }
+    }
+    counts
+}
+
+// the first pre-tokenizer boundary at or after `from` that does not cut through a special token
+// string, counting one that may continue past the end of data
+fn boundary(
+    pre_tokenizer: &dyn PreTokenizer,
+    specials: &[Vec<u8>],
+    data: &[u8],
+    from: usize,
+) -> usize {
+    let mut from = from;
+    loop {
+        let cut = pre_tokenizer.boundary(data, from);
+        if cut >= data.len() || !inside_special(specials, data, cut) {
+            return cut;
+        }
+        from = cut + 1;
+    }
+}
+
+fn inside_special(specials: &[Vec<u8>], data: &[u8], cut: usize) -> bool {
+    specials.iter().any(|special| {
+        (cut.saturating_sub(special.len().saturating_sub(1))..cut).any(|start| {
+            let rest = &data[start..];
+            match rest.len() >= special.len() {
+                true => rest.starts_with(special),
+                false => special.starts_with(rest),
+            }
+        })
+    })
+}
+
+// the pieces of data between occurrences of specials, longest specials first
+fn without_specials<'a>(data: &'a [u8], specials: &[Vec<u8>]) -> Vec<&'a [u8]> {
+    let mut first_bytes = [false; 256];
+    specials
+        .iter()
+        .filter_map(|special| special.first())
+        .for_each(|b| first_bytes[*b as usize] = true);
+
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let special = match first_bytes[data[i] as usize] {
+            true => specials.iter().find(|s| data[i..].starts_with(s)),
+            false => None,
+        };
+
+        match special {
+            Some(special) => {
+                pieces.push(&data[start..i]);
+                i += special.len();
+                start = i;
+            }
+            None => i += 1,
+        }
+    }
+    pieces.push(&data[start..]);
+    pieces
+}
+
+/// Word counts over a corpus that arrives in arbitrary chunks. Input is buffered up to the last
+/// pre-tokenizer boundary, everything before it is counted and dropped.
+pub(super) struct WordCounter<'a> {
+    pre_tokenizer: &'a dyn PreTokenizer,
+    specials: Vec<Vec<u8>>,
+    threads: usize,
+    pending: Vec<u8>,
+    counts: HashMap<Box<[u8]>, u64>,
+}
+
+impl<'a> WordCounter<'a> {
+    pub(super) fn new(
+        pre_tokenizer: &'a dyn PreTokenizer,
+        specials: Vec<Vec<u8>>,
+        threads: usize,
+    ) -> Self {
+        Self {
+            pre_tokenizer,
+            specials,
+            threads,
+            pending: Vec::new(),
+            counts: HashMap::new(),
+        }
+    }
+
+    pub(super) fn feed(&mut self, chunk: &[u8]) {
+        // a boundary depends on the chars around it, so rescan the last few pending bytes
+        let mut from = self.pending.len().saturating_sub(8);
+        self.pending.extend_from_slice(chunk);
+
+        let mut cut = 0;
+        loop {
+            let boundary = boundary(self.pre_tokenizer, &self.specials, &self.pending, from);
+            if boundary >= self.pending.len() {
+                break;
+            }
+            cut = boundary;
+            from = boundary + 1;
+        }
+
+        if cut > 0 {
+            self.count(cut);
+        }
+    }
+
+    /// Counts everything buffered, for the end of a continuous text.
+    pub(super) fn flush(&mut self) {
+        self.count(self.pending.len());
+    }
+
+    pub(super) fn into_counts(self) -> HashMap<Box<[u8]>, u64> {
+        self.counts
+    }
+
+    fn count(&mut self, end: usize) {
+        let counts = count_words(
+            self.pre_tokenizer,
+            &self.specials,
+            &self.pending[..end],
+            self.threads,
+        );
+        for (word, count) in counts {
+            match self.counts.get_mut(word) {
+                Some(total) => *total += count,
+                None => {
+                    self.counts.insert(word.into(), count);
+                }
+            }
+        }
+        self.pending.drain(..end);
+    }
+}
+
+/// Incremental pair statistics for BPE training. Words are deduplicated and carry their corpus
+/// frequency, pair counts are updated only for the words a merge touches, and the next merge is
+/// taken from a max-heap whose stale entries are skipped lazily.
+pub(super) struct MergeQueue {
+    words: Vec<(Vec<u32>, u64)>, // symbols of each distinct word and its frequency
+    pair_counts: HashMap<(u32, u32), u64>,
+    pair_words: HashMap<(u32, u32), HashSet<usize>>, // indices of the words a pair may occur in
+    heap: BinaryHeap<(u64, Reverse<(u32, u32)>)>,    // ties go to the smallest pair
+}
+
+impl MergeQueue {
+    /// Builds the initial pair counts, splitting the words across `threads` threads.
+    pub(super) fn new(words: Vec<(Vec<u32>, u64)>, threads: usize) -> Self {
+        let chunk_len = words.len().div_ceil(threads.max(1)).max(1);
+        let partials: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = words
+                .chunks(chunk_len)
+                .enumerate()
+                .map(|(c, chunk)| {
+                    scope.spawn(move || {
+                        let mut pair_counts: HashMap<(u32, u32), u64> = HashMap::new();
+                        let mut pair_words: HashMap<(u32, u32), HashSet<usize>> = HashMap::new();
+                        for (i, (symbols, count)) in chunk.iter().enumerate() {
+                            for window in symbols.windows(2) {
+                                let pair = (window[0], window[1]);
+                                *pair_counts.entry(pair).or_insert(0) += count;
+                                pair_words
+                                    .entry(pair)
+                                    .or_default()
+                                    .insert(c * chunk_len + i);
+                            }
+                        }
+                        (pair_counts, pair_words)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let mut pair_counts: HashMap<(u32, u32), u64> = HashMap::new();
+        let mut pair_words: HashMap<(u32, u32), HashSet<usize>> = HashMap::new();
+        for (counts, indices) in partials {
+            for (pair, count) in counts {
+                *pair_counts.entry(pair).or_insert(0) += count;
+            }
+            for (pair, words) in indices {
+                pair_words.entry(pair).or_default().extend(words);
+            }
+        }
+
+        let heap = pair_counts
+            .iter()
+            .map(|(pair, count)| (*count, Reverse(*pair)))
+            .collect();
+
+        Self {
+            words,
+            pair_counts,
+            pair_words,
+            heap,
+        }
+    }
+
+    /// Returns the most frequent pair, or `None` once no pairs are left.
+    pub(super) fn pop(&mut self) -> Option<(u32, u32)> {
+        while let Some((count, Reverse(pair))) = self.heap.pop() {
+            if self.pair_counts.get(&pair) == Some(&count) {
+                return Some(pair);
+            }
+        }
+        None
+    }
+
+    /// Replaces every occurrence of `pair` with `merged` and updates the counts of the pairs around
+    /// each occurrence.
+    pub(super) fn merge(&mut self, pair: (u32, u32), merged: u32) {
+        let mut affected: Vec<usize> = self
+            .pair_words
+            .remove(&pair)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        affected.sort_unstable();
+
+        let mut changed: HashSet<(u32, u32)> = HashSet::new();
+        for i in affected {
+            let (symbols, count) = &self.words[i];
+            let count = *count;
+            if !symbols.windows(2).any(|w| (w[0], w[1]) == pair) {
+                continue;
+            }
+
+            for window in symbols.windows(2) {
+                let old = (window[0], window[1]);
+                if let Some(c) = self.pair_counts.get_mut(&old) {
+                    *c -= count;
+                }
+                changed.insert(old);
+            }
+
+            let mut merged_symbols = Vec::with_capacity(symbols.len());
+            let mut j = 0;
+            while j < symbols.len() {
+                if j + 1 < symbols.len() && (symbols[j], symbols[j + 1]) == pair {
+                    merged_symbols.push(merged);
+                    j += 2;
+                } else {
+                    merged_symbols.push(symbols[j]);
+                    j += 1;
+                }
+            }
+
+            for window in merged_symbols.windows(2) {
+                let new = (window[0], window[1]);
+                *self.pair_counts.entry(new).or_insert(0) += count;
+                self.pair_words.entry(new).or_default().insert(i);
+                changed.insert(new);
+            }
+
+            self.words[i].0 = merged_symbols;
+        }
+
+        for pair in changed {
+            match self.pair_counts.get(&pair) {
+                Some(0) => {
+                    self.pair_counts.remove(&pair);
+                }
+                Some(count) => self.heap.push((*count, Reverse(pair))),
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::pretokenizer::PreTokenizerKind;
+
+    #[test]
+    fn streaming_buffers_stay_small_without_spaces() {
+        let corpora = [
+            "東京は今日も晴れ。明日は雨でしょう、傘を忘れずに！".repeat(2000),
+            "let x=f(a,b);if(x>1){y=g(x);}else{y=h(x);}".repeat(2000),
+        ];
+        for corpus in corpora.iter() {
+            for kind in [PreTokenizerKind::Gpt2, PreTokenizerKind::Cl100k] {
+                let mut counter = WordCounter::new(kind.get(), vec![b"<|eos|>".to_vec()], 2);
+                for chunk in corpus.as_bytes().chunks(1000) {
+                    counter.feed(chunk);
+                    assert!(counter.pending.len() < 2000, "{:?}", kind);
+                }
+                counter.flush();
+
+                let one_shot: HashMap<Box<[u8]>, u64> =
+                    count_words(kind.get(), &[b"<|eos|>".to_vec()], corpus.as_bytes(), 1)
+                        .into_iter()
+                        .map(|(word, count)| (word.into(), count))
+                        .collect();
+                assert_eq!(counter.into_counts(), one_shot);
+            }
+        }
+    }
+}
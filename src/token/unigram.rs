@@ -0,0 +1,714 @@
+use super::pretokenizer::PreTokenizerKind;
+use super::tokenizer::{self, SpecialToken, Tokenizer};
+use super::{train, utils};
+use rand::Rng;
+use std::{collections::HashMap, io::Read, thread};
+
+const MAGIC: &[u8; 8] = b"GPTRSUNI";
+const FORMAT_VERSION: u32 = 1;
+
+const MIN_EXPECTED_COUNT: f64 = 0.5; // pieces used less often than this are dropped after EM
+
+pub struct UnigramConfig {
+    pub vocab_size: usize,
+    pub special_tokens: Vec<SpecialToken>,
+    pub pre_tokenizer: PreTokenizerKind,
+    pub max_piece_len: usize, // in bytes
+    pub seed_size: usize,     // candidate pieces training starts from
+    pub shrink_factor: f64,   // fraction of pieces kept by each pruning round
+    pub em_iterations: usize, // per pruning round
+}
+
+impl Default for UnigramConfig {
+    fn default() -> Self {
+        Self {
+            vocab_size: 8000,
+            special_tokens: vec![SpecialToken::Eos],
+            pre_tokenizer: PreTokenizerKind::Gpt2,
+            max_piece_len: 16,
+            seed_size: 100_000,
+            shrink_factor: 0.75,
+            em_iterations: 2,
+        }
+    }
+}
+
+/// Unigram language model tokenizer, as in SentencePiece. Every piece has a log probability and
+/// each pre-tokenized word is split into the pieces with the highest total. All 256 single bytes
+/// are pieces, so any input can be encoded.
+pub struct UnigramTokenizer {
+    pieces: Vec<Box<[u8]>>, // id to piece bytes, special token reprs included
+    scores: Vec<f64>,       // log probability of each piece
+    piece_ids: HashMap<Box<[u8]>, u32>, // piece bytes to id, without special tokens
+    max_piece_len: usize,
+    config: UnigramConfig,
+    special_tokens: HashMap<SpecialToken, u32>,
+    built: bool,
+}
+
+impl UnigramTokenizer {
+    pub fn new(config: UnigramConfig) -> Result<Self, String> {
+        if config.vocab_size < 256 + config.special_tokens.len() {
+            return Err(
+                "Please use a vocabulary size of at least 256 plus the special tokens".to_string(),
+            );
+        }
+
+        if config.max_piece_len == 0 {
+            return Err("Pieces must be at least one byte long".to_string());
+        }
+
+        if !(config.shrink_factor > 0.0 && config.shrink_factor < 1.0) {
+            return Err("The shrink factor must be between 0 and 1".to_string());
+        }
+
+        for (i, token) in config.special_tokens.iter().enumerate() {
+            if *token == SpecialToken::Eow {
+                return Err("The unigram model has no end of word token".to_string());
+            }
+
+            if config.special_tokens[..i].contains(token) {
+                return Err(format!(
+                    "Special token {} is registered twice",
+                    String::from_utf8_lossy(&token.repr())
+                ));
+            }
+        }
+
+        Ok(Self {
+            pieces: Vec::new(),
+            scores: Vec::new(),
+            piece_ids: HashMap::new(),
+            max_piece_len: config.max_piece_len,
+            config,
+            special_tokens: HashMap::new(),
+            built: false,
+        })
+    }
+
+    pub fn special_token_id(&self, token: &SpecialToken) -> Option<u32> {
+        self.special_tokens.get(token).copied()
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Log probability of a piece, `None` for special tokens and unknown ids.
+    pub fn score(&self, id: u32) -> Option<f64> {
+        self.scores
+            .get(id as usize)
+            .copied()
+            .filter(|s| s.is_finite())
+    }
+
+    /// Splits every word into its most likely pieces and appends eos if configured.
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<u32>, String> {
+        let mut tokens = self.encode_ordinary(data)?;
+        if let Some(&eos_id) = self.special_tokens.get(&SpecialToken::Eos) {
+            tokens.push(eos_id);
+        }
+
+        Ok(tokens)
+    }
+
+    /// Splits every word into its most likely pieces, without adding eos.
+    pub fn encode_ordinary(&self, data: &[u8]) -> Result<Vec<u32>, String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        let mut tokens = Vec::new();
+        for (start, end) in self.config.pre_tokenizer.get().split(data) {
+            let word = &data[start..end];
+            let path = best_path(word, self.max_piece_len, |piece| {
+                self.piece_ids
+                    .get(piece)
+                    .map(|id| (*id as usize, self.scores[*id as usize]))
+            })
+            .ok_or_else(|| unsplittable(word))?;
+            tokens.extend(path.into_iter().map(|id| id as u32));
+        }
+
+        Ok(tokens)
+    }
+
+    /// Subword regularization: samples a segmentation of every word from the model instead of
+    /// taking the best one. `alpha` sharpens the distribution, 1.0 samples from the model itself
+    /// and values towards 0.0 approach a uniform choice among all segmentations.
+    pub fn encode_sampled<R: Rng>(
+        &self,
+        data: &[u8],
+        alpha: f64,
+        rng: &mut R,
+    ) -> Result<Vec<u32>, String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        let mut tokens = Vec::new();
+        for (start, end) in self.config.pre_tokenizer.get().split(data) {
+            let word = &data[start..end];
+            tokens.extend(
+                self.sample_word(word, alpha, rng)
+                    .ok_or_else(|| unsplittable(word))?,
+            );
+        }
+
+        if let Some(&eos_id) = self.special_tokens.get(&SpecialToken::Eos) {
+            tokens.push(eos_id);
+        }
+
+        Ok(tokens)
+    }
+
+    // forward filtering over the lattice of pieces, then sampling pieces back from the end. `None`
+    // if the pieces cannot cover the word.
+    fn sample_word<R: Rng>(&self, word: &[u8], alpha: f64, rng: &mut R) -> Option<Vec<u32>> {
+        let n = word.len();
+        let mut ending: Vec<Vec<(usize, u32)>> = vec![Vec::new(); n + 1]; // start and id
+        for start in 0..n {
+            for end in start + 1..=n.min(start + self.max_piece_len) {
+                if let Some(&id) = self.piece_ids.get(&word[start..end]) {
+                    ending[end].push((start, id));
+                }
+            }
+        }
+
+        let mut forward = vec![f64::NEG_INFINITY; n + 1];
+        forward[0] = 0.0;
+        for end in 1..=n {
+            for (start, id) in ending[end].iter() {
+                let score = forward[*start] + alpha * self.scores[*id as usize];
+                forward[end] = log_add(forward[end], score);
+            }
+        }
+
+        if forward[n] == f64::NEG_INFINITY {
+            return None;
+        }
+
+        let mut tokens = Vec::new();
+        let mut end = n;
+        while end > 0 {
+            let mut r: f64 = rng.random();
+            let mut choice = *ending[end].first()?;
+            for (start, id) in ending[end].iter() {
+                let score = forward[*start] + alpha * self.scores[*id as usize];
+                r -= (score - forward[end]).exp();
+                choice = (*start, *id);
+                if r <= 0.0 {
+                    break;
+                }
+            }
+            tokens.push(choice.1);
+            end = choice.0;
+        }
+
+        tokens.reverse();
+        Some(tokens)
+    }
+
+    /// Saves the pieces, their scores and the special tokens. Training settings are not kept.
+    pub fn save_to_binary(&self, path: &str) -> Result<(), String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        let mut payload = Vec::new();
+        utils::write_u32(&mut payload, self.config.vocab_size as u32)?;
+        payload.push(self.config.pre_tokenizer.to_u8()?);
+        tokenizer::write_special_tags(&mut payload, &self.config.special_tokens)?;
+        tokenizer::write_special_ids(&mut payload, &self.special_tokens)?;
+
+        utils::write_u32(&mut payload, self.pieces.len() as u32)?;
+        for (piece, score) in self.pieces.iter().zip(self.scores.iter()) {
+            utils::write_u32(&mut payload, piece.len() as u32)?;
+            payload.extend_from_slice(piece);
+            utils::write_u64(&mut payload, score.to_bits())?;
+        }
+
+        utils::write_checked(path, MAGIC, FORMAT_VERSION, &payload)
+    }
+
+    pub fn load_from_binary(path: &str) -> Result<Self, String> {
+        let (_, payload) = utils::read_checked(path, MAGIC, FORMAT_VERSION)?;
+        let mut reader = payload.as_slice();
+
+        let vocab_size = utils::read_u32(&mut reader)? as usize;
+        let mut buf = [0_u8; 1];
+        reader.read_exact(&mut buf).map_err(|e| e.to_string())?;
+        let mut tokenizer = Self::new(UnigramConfig {
+            vocab_size,
+            special_tokens: tokenizer::read_special_tags(&mut reader)?,
+            pre_tokenizer: PreTokenizerKind::from_u8(buf[0])?,
+            ..UnigramConfig::default()
+        })?;
+        tokenizer.special_tokens = tokenizer::read_special_ids(&mut reader)?;
+
+        let count = utils::read_u32(&mut reader)?;
+        for id in 0..count {
+            let len = utils::read_u32(&mut reader)? as usize;
+            let piece = utils::read_bytes(&mut reader, len)?;
+            let score = f64::from_bits(utils::read_u64(&mut reader)?);
+
+            if !tokenizer
+                .special_tokens
+                .values()
+                .any(|special| *special == id)
+            {
+                tokenizer
+                    .piece_ids
+                    .insert(piece.clone().into_boxed_slice(), id);
+            }
+            tokenizer.pieces.push(piece.into_boxed_slice());
+            tokenizer.scores.push(score);
+        }
+
+        if !reader.is_empty() {
+            return Err("Tokenizer file has trailing data".to_string());
+        }
+        tokenizer.check_tables()?;
+
+        tokenizer.max_piece_len = tokenizer
+            .piece_ids
+            .keys()
+            .map(|piece| piece.len())
+            .max()
+            .unwrap_or(1);
+        tokenizer.built = true;
+        Ok(tokenizer)
+    }
+
+    // rejects tables that do not fit together, as read from a damaged or foreign file. Every byte
+    // has to be a piece with a usable score, so any word can be segmented.
+    fn check_tables(&self) -> Result<(), String> {
+        let invalid = |what: &str| Err(format!("Tokenizer file has an invalid {}", what));
+
+        if self
+            .special_tokens
+            .values()
+            .any(|id| *id as usize >= self.pieces.len())
+            || self
+                .config
+                .special_tokens
+                .iter()
+                .any(|token| !self.special_tokens.contains_key(token))
+        {
+            return invalid("special token table");
+        }
+
+        if self
+            .piece_ids
+            .iter()
+            .any(|(piece, id)| piece.is_empty() || !self.scores[*id as usize].is_finite())
+        {
+            return invalid("piece table");
+        }
+
+        if let Some(b) = (0..=255_u8).find(|b| !self.piece_ids.contains_key([*b].as_slice())) {
+            return Err(format!("Tokenizer file has no piece for byte {:#04x}", b));
+        }
+        Ok(())
+    }
+
+    /// Concatenates the pieces, skipping special tokens.
+    pub fn decode(&self, tokens: &[u32]) -> Result<Vec<u8>, String> {
+        if !self.built {
+            return Err("Tokenizer not built yet".to_string());
+        }
+
+        let mut bytes = Vec::new();
+        for token in tokens.iter() {
+            let Some(piece) = self.pieces.get(*token as usize) else {
+                return Err(format!("Unknown token id {}", token));
+            };
+
+            if !self.special_tokens.values().any(|id| id == token) {
+                bytes.extend_from_slice(piece);
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub fn build(&mut self, data: &[u8]) {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let specials: Vec<Vec<u8>> = self
+            .config
+            .special_tokens
+            .iter()
+            .map(|token| token.repr())
+            .collect();
+        let word_counts =
+            train::count_words(self.config.pre_tokenizer.get(), &specials, data, threads);
+
+        let mut words: Vec<(&[u8], u64)> = word_counts.into_iter().collect();
+        words.sort_unstable();
+        self.train(&words);
+    }
+
+    // EM over the seed pieces, pruning the pieces whose removal costs the least likelihood until
+    // the vocabulary fits
+    fn train(&mut self, words: &[(&[u8], u64)]) {
+        let target = self.config.vocab_size - 256 - self.config.special_tokens.len();
+        let mut model = Model::seed(
+            words,
+            self.config.max_piece_len,
+            self.config.seed_size.max(target),
+        );
+
+        loop {
+            for _ in 0..self.config.em_iterations.max(1) {
+                model.em_step(words);
+            }
+
+            let candidates = model.pieces.len() - 256;
+            if candidates <= target {
+                break;
+            }
+
+            let keep = target.max((candidates as f64 * self.config.shrink_factor) as usize);
+            model.prune(words, keep);
+        }
+
+        // bytes keep their value as id, special tokens follow, then pieces from most likely
+        let mut order: Vec<usize> = (256..model.pieces.len()).collect();
+        order.sort_by(|a, b| {
+            model.scores[*b]
+                .total_cmp(&model.scores[*a])
+                .then(model.pieces[*a].cmp(&model.pieces[*b]))
+        });
+
+        self.pieces.clear();
+        self.scores.clear();
+        self.piece_ids.clear();
+        self.special_tokens.clear();
+        for i in 0..256 {
+            self.add_piece(&model.pieces[i], model.scores[i]);
+        }
+        for token in self.config.special_tokens.iter() {
+            self.special_tokens
+                .insert(token.clone(), self.pieces.len() as u32);
+            self.pieces.push(token.repr().into_boxed_slice());
+            self.scores.push(f64::NEG_INFINITY);
+        }
+        for i in order {
+            self.add_piece(&model.pieces[i], model.scores[i]);
+        }
+
+        self.max_piece_len = self
+            .piece_ids
+            .keys()
+            .map(|piece| piece.len())
+            .max()
+            .unwrap_or(1);
+        self.built = true;
+    }
+
+    fn add_piece(&mut self, piece: &[u8], score: f64) {
+        self.piece_ids
+            .insert(piece.into(), self.pieces.len() as u32);
+        self.pieces.push(piece.into());
+        self.scores.push(score);
+    }
+}
+
+impl Tokenizer for UnigramTokenizer {
+    fn encode(&self, data: &[u8]) -> Result<Vec<u32>, String> {
+        UnigramTokenizer::encode(self, data)
+    }
+
+    fn encode_ordinary(&self, data: &[u8]) -> Result<Vec<u32>, String> {
+        UnigramTokenizer::encode_ordinary(self, data)
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<Vec<u8>, String> {
+        UnigramTokenizer::decode(self, tokens)
+    }
+
+    fn vocab_size(&self) -> usize {
+        UnigramTokenizer::vocab_size(self)
+    }
+
+    fn special_token_id(&self, token: &SpecialToken) -> Option<u32> {
+        UnigramTokenizer::special_token_id(self, token)
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        self.save_to_binary(path)
+    }
+
+    fn load(path: &str) -> Result<Self, String> {
+        Self::load_from_binary(path)
+    }
+}
+
+// pieces being trained, the first 256 are the single bytes and are never pruned
+struct Model {
+    pieces: Vec<Vec<u8>>,
+    scores: Vec<f64>,
+    ids: HashMap<Vec<u8>, usize>,
+    max_len: usize,
+}
+
+impl Model {
+    // all bytes plus the most frequent multi-byte substrings of the words, by frequency times
+    // length. Substrings that cut through a UTF-8 character are not candidates.
+    fn seed(words: &[(&[u8], u64)], max_len: usize, seed_size: usize) -> Self {
+        let mut byte_counts = [0_u64; 256];
+        let mut counts: HashMap<&[u8], u64> = HashMap::new();
+        for (word, count) in words.iter() {
+            for start in 0..word.len() {
+                byte_counts[word[start] as usize] += count;
+                for end in start + 2..=word.len().min(start + max_len) {
+                    let piece = &word[start..end];
+                    if std::str::from_utf8(piece).is_ok() {
+                        *counts.entry(piece).or_insert(0) += count;
+                    }
+                }
+            }
+        }
+
+        let mut seeds: Vec<(&[u8], u64)> = counts.into_iter().collect();
+        seeds.sort_by(|a, b| {
+            (b.1 * b.0.len() as u64)
+                .cmp(&(a.1 * a.0.len() as u64))
+                .then(a.0.cmp(b.0))
+        });
+        seeds.truncate(seed_size);
+
+        let pieces: Vec<(Vec<u8>, u64)> = (0..=255_u8)
+            .map(|b| (vec![b], byte_counts[b as usize]))
+            .chain(
+                seeds
+                    .into_iter()
+                    .map(|(piece, count)| (piece.to_vec(), count)),
+            )
+            .collect();
+        let total: u64 = pieces.iter().map(|(_, count)| count).sum();
+
+        let mut model = Model {
+            pieces: Vec::with_capacity(pieces.len()),
+            scores: Vec::with_capacity(pieces.len()),
+            ids: HashMap::new(),
+            max_len,
+        };
+        for (piece, count) in pieces {
+            model.pieces.push(piece);
+            model
+                .scores
+                .push(((count.max(1)) as f64 / total.max(1) as f64).ln());
+        }
+        model.index();
+        model
+    }
+
+    fn index(&mut self) {
+        self.ids = self
+            .pieces
+            .iter()
+            .enumerate()
+            .map(|(i, piece)| (piece.clone(), i))
+            .collect();
+    }
+
+    fn best_path(&self, word: &[u8], skip: Option<usize>) -> Option<Vec<usize>> {
+        best_path(word, self.max_len, |piece| {
+            self.ids
+                .get(piece)
+                .filter(|id| Some(**id) != skip)
+                .map(|id| (*id, self.scores[*id]))
+        })
+    }
+
+    // expected piece counts over all segmentations by forward-backward, then new log
+    // probabilities from them. Rarely used pieces other than bytes are dropped.
+    fn em_step(&mut self, words: &[(&[u8], u64)]) {
+        let mut expected = vec![0.0_f64; self.pieces.len()];
+        for (word, count) in words.iter() {
+            let n = word.len();
+            let mut edges = Vec::new(); // start, end and piece, ordered by start
+            for start in 0..n {
+                for end in start + 1..=n.min(start + self.max_len) {
+                    if let Some(&id) = self.ids.get(&word[start..end]) {
+                        edges.push((start, end, id));
+                    }
+                }
+            }
+
+            let mut forward = vec![f64::NEG_INFINITY; n + 1];
+            forward[0] = 0.0;
+            for (start, end, id) in edges.iter() {
+                forward[*end] = log_add(forward[*end], forward[*start] + self.scores[*id]);
+            }
+
+            let mut backward = vec![f64::NEG_INFINITY; n + 1];
+            backward[n] = 0.0;
+            for (start, end, id) in edges.iter().rev() {
+                backward[*start] = log_add(backward[*start], backward[*end] + self.scores[*id]);
+            }
+
+            let z = forward[n];
+            for (start, end, id) in edges.iter() {
+                let posterior = forward[*start] + self.scores[*id] + backward[*end] - z;
+                expected[*id] += *count as f64 * posterior.exp();
+            }
+        }
+
+        let kept: Vec<usize> = (0..self.pieces.len())
+            .filter(|i| *i < 256 || expected[*i] >= MIN_EXPECTED_COUNT)
+            .collect();
+        let total: f64 = kept
+            .iter()
+            .map(|i| expected[*i].max(MIN_EXPECTED_COUNT))
+            .sum();
+        self.scores = kept
+            .iter()
+            .map(|i| (expected[*i].max(MIN_EXPECTED_COUNT) / total).ln())
+            .collect();
+        self.pieces = kept
+            .iter()
+            .map(|i| std::mem::take(&mut self.pieces[*i]))
+            .collect();
+        self.index();
+    }
+
+    // keeps the `keep` multi-byte pieces whose removal would lower the likelihood the most. The
+    // loss of a piece is how often the best segmentations use it, times how much worse its best
+    // segmentation without it scores.
+    fn prune(&mut self, words: &[(&[u8], u64)], keep: usize) {
+        let mut uses = vec![0_u64; self.pieces.len()];
+        for (word, count) in words.iter() {
+            for id in self.best_path(word, None).into_iter().flatten() {
+                uses[id] += count;
+            }
+        }
+
+        let mut losses: Vec<(f64, usize)> = (256..self.pieces.len())
+            .map(|id| {
+                if uses[id] == 0 {
+                    return (0.0, id);
+                }
+                // a piece nothing else can replace is never pruned
+                let alternative: f64 = self
+                    .best_path(&self.pieces[id], Some(id))
+                    .map_or(f64::NEG_INFINITY, |path| {
+                        path.iter().map(|other| self.scores[*other]).sum()
+                    });
+                (uses[id] as f64 * (self.scores[id] - alternative), id)
+            })
+            .collect();
+        losses.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        let mut kept: Vec<usize> = losses.iter().take(keep).map(|(_, id)| *id).collect();
+        kept.sort_unstable();
+        let kept: Vec<usize> = (0..256).chain(kept).collect();
+
+        self.scores = kept.iter().map(|i| self.scores[*i]).collect();
+        self.pieces = kept
+            .iter()
+            .map(|i| std::mem::take(&mut self.pieces[*i]))
+            .collect();
+        self.index();
+    }
+}
+
+// Viterbi segmentation of a word into the pieces with the highest total score. `piece` looks up
+// the id and score of a candidate piece. `None` if the pieces cannot cover the word.
+fn best_path(
+    word: &[u8],
+    max_len: usize,
+    piece: impl Fn(&[u8]) -> Option<(usize, f64)>,
+) -> Option<Vec<usize>> {
+    let n = word.len();
+    let mut best = vec![(f64::NEG_INFINITY, 0, 0); n + 1]; // score, start and piece of the last step
+    best[0].0 = 0.0;
+    for start in 0..n {
+        if best[start].0 == f64::NEG_INFINITY {
+            continue;
+        }
+
+        for end in start + 1..=n.min(start + max_len) {
+            if let Some((id, score)) = piece(&word[start..end]) {
+                let total = best[start].0 + score;
+                if total > best[end].0 {
+                    best[end] = (total, start, id);
+                }
+            }
+        }
+    }
+
+    if best[n].0 == f64::NEG_INFINITY {
+        return None;
+    }
+
+    let mut path = Vec::new();
+    let mut end = n;
+    while end > 0 {
+        let (_, start, id) = best[end];
+        path.push(id);
+        end = start;
+    }
+    path.reverse();
+    Some(path)
+}
+
+fn unsplittable(word: &[u8]) -> String {
+    format!(
+        "Word {:?} cannot be split into pieces",
+        String::from_utf8_lossy(word)
+    )
+}
+
+// ln(e^a + e^b) without overflow
+fn log_add(a: f64, b: f64) -> f64 {
+    if a == f64::NEG_INFINITY {
+        return b;
+    }
+    if b == f64::NEG_INFINITY {
+        return a;
+    }
+    a.max(b) + (-(a - b).abs()).exp().ln_1p()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::utils::TempFile;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn missing_byte_pieces_are_errors() {
+        let mut tokenizer = UnigramTokenizer::new(UnigramConfig {
+            vocab_size: 300,
+            seed_size: 2000,
+            ..UnigramConfig::default()
+        })
+        .unwrap();
+        tokenizer.build(
+            "the cat sat on the mat, the dog sat on the log. "
+                .repeat(20)
+                .as_bytes(),
+        );
+
+        let path = TempFile::new("unigram.bin");
+        tokenizer.save_to_binary(&path).unwrap();
+        assert!(UnigramTokenizer::load_from_binary(&path).is_ok());
+
+        tokenizer.piece_ids.remove(b"z".as_slice());
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(tokenizer.encode(b"the zoo").is_err());
+        assert!(tokenizer.encode_sampled(b"the zoo", 1.0, &mut rng).is_err());
+        assert!(tokenizer.encode(b"the cat").is_ok());
+
+        let id = tokenizer.pieces.iter().position(|p| **p == *b"z").unwrap();
+        tokenizer.pieces[id] = Box::new(*b"zz");
+        tokenizer.save_to_binary(&path).unwrap();
+        assert!(UnigramTokenizer::load_from_binary(&path).is_err());
+
+        tokenizer.pieces[id] = Box::new(*b"z");
+        tokenizer.scores[id] = f64::NAN;
+        tokenizer.save_to_binary(&path).unwrap();
+        assert!(UnigramTokenizer::load_from_binary(&path).is_err());
+    }
+}
@@ -1,30 +1,162 @@
 use std::{
-    fs::File,
+    fs,
     io::{Read, Write},
 };
 
-pub(super) fn write_u32(writer: &mut dyn Write, value: u32) -> Result<(), String> {
+pub(crate) fn write_u32(writer: &mut dyn Write, value: u32) -> Result<(), String> {
     writer
         .write_all(&(value).to_le_bytes())
         .map_err(|e| e.to_string())?;
     Ok(())
 }
 
-pub(super) fn read_u32(reader: &mut dyn Read) -> Result<u32, String> {
+pub(crate) fn read_u32(reader: &mut dyn Read) -> Result<u32, String> {
     let mut buf = [0_u8; 4];
     reader.read_exact(&mut buf).map_err(|e| e.to_string())?;
     Ok(u32::from_le_bytes(buf))
 }
 
-pub(super) fn write_u64(writer: &mut dyn Write, value: u64) -> Result<(), String> {
+pub(crate) fn write_u64(writer: &mut dyn Write, value: u64) -> Result<(), String> {
     writer
         .write_all(&(value).to_le_bytes())
         .map_err(|e| e.to_string())?;
     Ok(())
 }
 
-pub(super) fn read_u64(reader: &mut dyn Read) -> Result<u64, String> {
+pub(crate) fn read_u64(reader: &mut dyn Read) -> Result<u64, String> {
     let mut bytes = [0u8; 8];
     reader.read_exact(&mut bytes).map_err(|e| e.to_string())?;
     Ok(u64::from_le_bytes(bytes))
 }
+
+/// Reads exactly `len` bytes. The buffer grows with the data actually read, so a corrupt length
+/// fails at the end of the input instead of allocating it up front.
+pub(crate) fn read_bytes(reader: &mut dyn Read, len: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    reader
+        .take(len as u64)
+        .read_to_end(&mut bytes)
+        .map_err(|e| e.to_string())?;
+    if bytes.len() != len {
+        return Err("Unexpected end of input".to_string());
+    }
+    Ok(bytes)
+}
+
+/// CRC-32 (IEEE) checksum.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Decodes standard base64 with optional padding.
+pub(crate) fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut buf = 0_u32;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return Err(format!("Invalid base64 character {:?}", c as char)),
+        };
+        buf = (buf << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+/// Writes `payload` behind an 8 byte magic and a format version, followed by a CRC-32 of
+/// everything before it.
+pub(crate) fn write_checked(
+    path: &str,
+    magic: &[u8; 8],
+    version: u32,
+    payload: &[u8],
+) -> Result<(), String> {
+    let mut out = Vec::with_capacity(payload.len() + 16);
+    out.extend_from_slice(magic);
+    write_u32(&mut out, version)?;
+    out.extend_from_slice(payload);
+    let checksum = crc32(&out);
+    write_u32(&mut out, checksum)?;
+    fs::write(path, out).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Reads a file written by `write_checked`, returning its version and payload.
+pub(crate) fn read_checked(
+    path: &str,
+    magic: &[u8; 8],
+    max_version: u32,
+) -> Result<(u32, Vec<u8>), String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    if bytes.len() < magic.len() + 8 || !bytes.starts_with(magic) {
+        return Err(format!("{} is not a tokenizer file of this kind", path));
+    }
+
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32(body) != read_u32(&mut &checksum[..])? {
+        return Err(format!("{}: checksum mismatch", path));
+    }
+
+    let mut reader = &body[magic.len()..];
+    let version = read_u32(&mut reader)?;
+    if version == 0 || version > max_version {
+        return Err(format!("{}: unsupported format version {}", path, version));
+    }
+    Ok((version, reader.to_vec()))
+}
+
+/// A file in the temp directory for tests, deleted when this is dropped.
+#[cfg(test)]
+pub(crate) struct TempFile(String);
+
+#[cfg(test)]
+impl TempFile {
+    pub(crate) fn new(name: &str) -> Self {
+        let name = format!("gpt-rs-{}-{}", std::process::id(), name);
+        Self(
+            std::env::temp_dir()
+                .join(name)
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempFile {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TempFile {
+    fn as_ref(&self) -> &std::path::Path {
+        self.0.as_ref()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}